@@ -0,0 +1,82 @@
+//! Handing 10M short lines to the threads like `main` does, one command per job against the
+//! batches of `--dispatch-batch`. The command only looks at its line, so that the time is the
+//! overhead of the dispatch: the closure, the `Arc` of the lines, the queue and the pool.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::{
+    hint::black_box,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use threadpool::ThreadPool;
+
+#[path = "../src/semaphore.rs"]
+mod semaphore;
+
+use semaphore::Semaphore;
+
+const LINES: usize = 10_000_000;
+const THREADS: usize = 4;
+
+type Job = (Arc<Vec<(String, i32, Option<String>)>>, usize, usize);
+
+fn input() -> Vec<String> {
+    (0..LINES).map(|i| format!("line {}", i)).collect()
+}
+
+/// Returns the total length of the lines, as seen by the commands
+fn dispatch(lines: &[String], dispatch_batch: usize) -> usize {
+    let pool = ThreadPool::new(THREADS);
+    let queue = Arc::new(Semaphore::new(THREADS * 16));
+    let total = Arc::new(AtomicUsize::new(0));
+
+    let submit = |jobs: Vec<Job>| {
+        let total = total.clone();
+        let permit = queue.acquire();
+        pool.execute(move || {
+            let _permit = permit;
+            for (lines, _repeat, _seq) in jobs {
+                let len = lines.iter().map(|(line, _, _)| line.len()).sum();
+                total.fetch_add(black_box(len), Ordering::Relaxed);
+            }
+        });
+    };
+
+    let mut pending = Vec::with_capacity(dispatch_batch);
+    for (seq, line) in lines.iter().enumerate() {
+        pending.push((Arc::new(vec![(line.clone(), seq as i32, None)]), 1, seq));
+        if pending.len() >= dispatch_batch {
+            submit(std::mem::replace(
+                &mut pending,
+                Vec::with_capacity(dispatch_batch),
+            ));
+        }
+    }
+    if !pending.is_empty() {
+        submit(pending);
+    }
+    pool.join();
+    total.load(Ordering::Relaxed)
+}
+
+fn bench_dispatch(c: &mut Criterion) {
+    let lines = input();
+    let expected = lines.iter().map(String::len).sum::<usize>();
+    assert_eq!(dispatch(&lines, 1), expected);
+    assert_eq!(dispatch(&lines, 64), expected);
+
+    let mut group = c.benchmark_group("dispatch");
+    group.throughput(Throughput::Elements(LINES as u64));
+    group.sample_size(10);
+    for dispatch_batch in [1, 64] {
+        group.bench_function(format!("--dispatch-batch {}", dispatch_batch), |b| {
+            b.iter(|| dispatch(&lines, dispatch_batch))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_dispatch);
+criterion_main!(benches);
@@ -0,0 +1,130 @@
+//! The read loop of `main`, with one buffer reused for every record against a new buffer for
+//! each of them. Half of the lines are filtered out before they are decoded, like with
+//! `--match`. `cargo bench` prints the allocations per line along with the time.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    hint::black_box,
+    io::Cursor,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+#[path = "../src/read.rs"]
+mod read;
+
+use read::{read_record, trim_record_end};
+
+const LINES: usize = 1_000_000;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Short lines, every other one ending with CRLF
+fn input() -> Vec<u8> {
+    (0..LINES)
+        .map(|i| {
+            if i % 2 == 0 {
+                format!("line {} of the input\n", i)
+            } else {
+                format!("line {} of the input\r\n", i)
+            }
+        })
+        .collect::<String>()
+        .into_bytes()
+}
+
+fn is_selected(count: usize) -> bool {
+    count.is_multiple_of(2)
+}
+
+/// Read all the records like `main` does, the lines are only copied into a String to be dispatched
+fn read_reused(input: &[u8]) -> usize {
+    let mut reader = Cursor::new(input);
+    let mut buffer = Vec::with_capacity(1024);
+    let mut count = 0;
+    loop {
+        buffer.clear();
+        if read_record(&mut reader, b"\n", &mut buffer, None).unwrap() == 0 {
+            return count;
+        }
+        trim_record_end(&mut buffer, b"\n", false);
+        if is_selected(count) {
+            black_box(std::str::from_utf8(&buffer).unwrap().to_string());
+        }
+        count += 1;
+    }
+}
+
+/// The same with a new buffer for every record, which becomes the String of the line
+fn read_fresh(input: &[u8]) -> usize {
+    let mut reader = Cursor::new(input);
+    let mut count = 0;
+    loop {
+        let mut buffer = Vec::with_capacity(1024);
+        if read_record(&mut reader, b"\n", &mut buffer, None).unwrap() == 0 {
+            return count;
+        }
+        trim_record_end(&mut buffer, b"\n", false);
+        if is_selected(count) {
+            black_box(String::from_utf8(buffer).unwrap());
+        }
+        count += 1;
+    }
+}
+
+/// The number of allocations and of allocated bytes per line
+fn allocations_per_line(read: fn(&[u8]) -> usize, input: &[u8]) -> (f64, f64) {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+    let lines = read(input) as f64;
+    (
+        (ALLOCATIONS.load(Ordering::Relaxed) - allocations) as f64 / lines,
+        (ALLOCATED_BYTES.load(Ordering::Relaxed) - bytes) as f64 / lines,
+    )
+}
+
+fn bench_read_loop(c: &mut Criterion) {
+    let input = input();
+    // every record is read, whatever is done with it
+    assert_eq!(read_reused(&input), LINES);
+    assert_eq!(read_fresh(&input), LINES);
+
+    for (name, read) in [
+        ("reused buffer", read_reused as fn(&[u8]) -> usize),
+        ("buffer per record", read_fresh),
+    ] {
+        let (allocations, bytes) = allocations_per_line(read, &input);
+        println!(
+            "{}: {:.2} allocations and {:.0} bytes per line",
+            name, allocations, bytes
+        );
+    }
+
+    let mut group = c.benchmark_group("read loop");
+    group.throughput(Throughput::Elements(LINES as u64));
+    group.sample_size(20);
+    group.bench_function("reused buffer", |b| b.iter(|| read_reused(&input)));
+    group.bench_function("buffer per record", |b| b.iter(|| read_fresh(&input)));
+    group.finish();
+}
+
+criterion_group!(benches, bench_read_loop);
+criterion_main!(benches);
@@ -0,0 +1,39 @@
+use regex::Regex;
+
+// red, green, yellow, blue, magenta and cyan, cycled through by the number of the group
+const COLORS: &[u8] = &[31, 32, 33, 34, 35, 36];
+const RESET: &str = "\x1b[0m";
+
+/// Color the parts of the line captured by the groups of the pattern, the groups numbered in a
+/// row across all the matches like the fields are. A nested group is colored over the group
+/// containing it, the rest of the line is left as it is.
+pub fn highlight(line: &str, pattern: &Regex, max_matches: usize) -> String {
+    let mut colors = vec![None; line.len()];
+    let mut group = 0;
+    for caps in pattern.captures_iter(line).take(max_matches) {
+        for mat in caps.iter().skip(1).flatten() {
+            let color = COLORS[group % COLORS.len()];
+            colors[mat.start()..mat.end()].fill(Some(color));
+            group += 1;
+        }
+    }
+
+    let mut highlighted = String::with_capacity(line.len() * 2);
+    let mut start = 0;
+    // group boundaries are character boundaries, so are the ends of the runs
+    while start < line.len() {
+        let color = colors[start];
+        let end = colors[start..]
+            .iter()
+            .position(|&c| c != color)
+            .map_or(line.len(), |len| start + len);
+        match color {
+            Some(color) => {
+                highlighted.push_str(&format!("\x1b[{}m{}{}", color, &line[start..end], RESET))
+            }
+            None => highlighted.push_str(&line[start..end]),
+        }
+        start = end;
+    }
+    highlighted
+}
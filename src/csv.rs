@@ -0,0 +1,27 @@
+/// Split a line of CSV into its fields, for example:
+///
+/// `a,"b,c","say ""hi"""` results in `a`, `b,c` and `say "hi"`
+///
+/// A quoted field may contain the delimiter and quotes escaped by doubling them. Text after the
+/// closing quote is kept as part of the field, an unterminated quote runs to the end of the line.
+pub fn split(line: &str, delimiter: char) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut chars = line.chars().peekable();
+    let mut in_quotes = false;
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if in_quotes => in_quotes = false,
+            '"' if field.is_empty() => in_quotes = true,
+            ch if ch == delimiter && !in_quotes => fields.push(std::mem::take(&mut field)),
+            ch => field.push(ch),
+        }
+    }
+    fields.push(field);
+    fields
+}
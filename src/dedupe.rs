@@ -0,0 +1,37 @@
+use std::collections::{HashSet, VecDeque};
+
+/// The keys of the lines already seen for `--dedupe`. All of them are kept unless there is a
+/// window, then only the last `window` distinct keys.
+pub struct Dedupe {
+    keys: HashSet<String>,
+    order: VecDeque<String>, // oldest first, only with a window
+    window: Option<usize>,
+}
+
+impl Dedupe {
+    pub fn new(window: Option<usize>) -> Self {
+        Dedupe {
+            keys: HashSet::new(),
+            order: VecDeque::new(),
+            window,
+        }
+    }
+
+    /// Returns false if the key was seen before
+    pub fn insert(&mut self, key: String) -> bool {
+        if self.keys.contains(&key) {
+            return false;
+        }
+
+        if let Some(window) = self.window {
+            if self.order.len() == window {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.keys.remove(&oldest);
+                }
+            }
+            self.order.push_back(key.clone());
+        }
+        self.keys.insert(key);
+        true
+    }
+}
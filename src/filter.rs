@@ -0,0 +1,582 @@
+use md5::Md5;
+use regex::{Regex, RegexBuilder};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cmp::max, cmp::min, convert::TryFrom, path::Path};
+
+const FILTER_NAMES: &[&str] = &[
+    "upper",
+    "lower",
+    "trim",
+    "basename",
+    "dirname",
+    "nth",
+    "slice",
+    "sub",
+    "pad",
+    "on",
+    "join",
+    "chunks",
+    "nonempty",
+    "urlencode",
+    "urldecode",
+    "json",
+    "add",
+    "mul",
+    "mod",
+    "md5",
+    "sha1",
+    "sha256",
+    "bucket",
+    "commas",
+    "round",
+];
+
+/// Filters transform the content of a field before it is substituted, for example:
+///
+/// "{1|trim|upper}" trims the first field and converts it to uppercase
+///
+/// Most filters transform every value on its own, others like `nth` select from all the values
+/// produced by a split range, or like `on` split them into more values.
+#[derive(Clone, Debug)]
+pub enum Filter {
+    Upper,
+    Lower,
+    Trim,
+    Basename,
+    Dirname,
+    Nth(i64, i64), // 1-based and inclusive, negative index counts from the end
+    Slice(Option<i64>, Option<i64>), // characters, 0-based and exclusive like in Python
+    Sub(Regex, String, bool), // pattern, replacement, replace all matches
+    Pad(usize),    // width of the zero-padded number
+    On(String),    // the inner separator, whitespace if empty
+    Join(String),
+    Chunks(usize), // characters per chunk
+    NonEmpty,
+    UrlEncode(bool), // keep the characters allowed in paths, like `/`
+    UrlDecode,
+    Json(bool), // keep the quotes around the string
+    Arithmetic(Operator, i64),
+    Hash(Algorithm, Option<usize>), // number of hex digits kept
+    Bucket(u64),                    // number of buckets
+    Commas,
+    Round(usize), // number of decimals
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Operator {
+    Add,
+    Sub,
+    Mul,
+    Mod,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Algorithm {
+    Md5,
+    Sha1,
+    Sha256,
+}
+
+// the largest width of `pad` and number of decimals of `round`, more is surely a mistake and
+// could run out of memory
+const MAX_WIDTH: usize = 1024;
+
+impl Filter {
+    /// `ignore_case` applies to the regex of `sub`
+    pub fn parse(spec: &str, ignore_case: bool) -> Result<Self, String> {
+        let spec = spec.trim();
+        let (name, arg) = match spec.find(':') {
+            Some(pos) => (&spec[..pos], Some(&spec[(pos + 1)..])),
+            None => (spec, None),
+        };
+
+        match (name, arg) {
+            ("upper", None) => Ok(Filter::Upper),
+            ("lower", None) => Ok(Filter::Lower),
+            ("trim", None) => Ok(Filter::Trim),
+            ("basename", None) => Ok(Filter::Basename),
+            ("dirname", None) => Ok(Filter::Dirname),
+            ("nonempty", None) => Ok(Filter::NonEmpty),
+            ("urlencode", None) => Ok(Filter::UrlEncode(false)),
+            ("urlencode", Some("path")) => Ok(Filter::UrlEncode(true)),
+            ("urldecode", None) => Ok(Filter::UrlDecode),
+            ("json", None) => Ok(Filter::Json(false)),
+            ("json", Some("quoted")) => Ok(Filter::Json(true)),
+            ("md5", arg) => parse_hash(Algorithm::Md5, arg, spec),
+            ("sha1", arg) => parse_hash(Algorithm::Sha1, arg, spec),
+            ("sha256", arg) => parse_hash(Algorithm::Sha256, arg, spec),
+            ("bucket", Some(arg)) => match arg.parse() {
+                Ok(buckets) if buckets > 0 => Ok(Filter::Bucket(buckets)),
+                _ => Err(invalid_argument(spec)),
+            },
+            ("commas", None) => Ok(Filter::Commas),
+            ("round", arg) => parse_width(arg.unwrap_or("0"), spec).map(Filter::Round),
+            ("on", Some(arg)) => Ok(Filter::On(arg.to_string())),
+            ("chunks", Some(arg)) => match arg.parse() {
+                Ok(width) if width > 0 => Ok(Filter::Chunks(width)),
+                _ => Err(invalid_argument(spec)),
+            },
+            ("join", arg) => Ok(Filter::Join(arg.unwrap_or(" ").to_string())),
+            ("nth", Some(arg)) => parse_nth(arg).ok_or_else(|| invalid_argument(spec)),
+            ("slice", Some(arg)) => parse_slice(arg).ok_or_else(|| invalid_argument(spec)),
+            ("pad", Some(arg)) => parse_width(arg, spec).map(Filter::Pad),
+            ("add", Some(arg)) => parse_arithmetic(Operator::Add, arg, spec),
+            ("mul", Some(arg)) => parse_arithmetic(Operator::Mul, arg, spec),
+            ("mod", Some(arg)) => parse_arithmetic(Operator::Mod, arg, spec),
+            // no sed expression is a number
+            ("sub", Some(arg)) if arg.parse::<i64>().is_ok() => {
+                parse_arithmetic(Operator::Sub, arg, spec)
+            }
+            ("sub", Some(arg)) => parse_sub(arg, ignore_case).map_err(|error| match error {
+                Some(error) => format!("invalid regex in filter {}: {}", spec, error),
+                None => invalid_argument(spec),
+            }),
+            _ if FILTER_NAMES.contains(&name) => Err(invalid_argument(spec)),
+            _ => Err(format!("unknown filter: {}", name)),
+        }
+    }
+
+    /// The name in the template, without the argument
+    pub fn name(&self) -> &'static str {
+        match self {
+            Filter::Upper => "upper",
+            Filter::Lower => "lower",
+            Filter::Trim => "trim",
+            Filter::Basename => "basename",
+            Filter::Dirname => "dirname",
+            Filter::Nth(..) => "nth",
+            Filter::Slice(..) => "slice",
+            Filter::Sub(..) | Filter::Arithmetic(Operator::Sub, _) => "sub",
+            Filter::Pad(_) => "pad",
+            Filter::On(_) => "on",
+            Filter::Join(_) => "join",
+            Filter::Chunks(_) => "chunks",
+            Filter::NonEmpty => "nonempty",
+            Filter::UrlEncode(_) => "urlencode",
+            Filter::UrlDecode => "urldecode",
+            Filter::Json(_) => "json",
+            Filter::Arithmetic(Operator::Add, _) => "add",
+            Filter::Arithmetic(Operator::Mul, _) => "mul",
+            Filter::Arithmetic(Operator::Mod, _) => "mod",
+            Filter::Hash(Algorithm::Md5, _) => "md5",
+            Filter::Hash(Algorithm::Sha1, _) => "sha1",
+            Filter::Hash(Algorithm::Sha256, _) => "sha256",
+            Filter::Bucket(_) => "bucket",
+            Filter::Commas => "commas",
+            Filter::Round(_) => "round",
+        }
+    }
+
+    /// Filter all the values of a field
+    pub fn apply_all<'a>(&self, values: Vec<Cow<'a, str>>) -> Vec<Cow<'a, str>> {
+        match *self {
+            Filter::Nth(left, right) => {
+                let len = values.len();
+                let left = max(translate_neg_index(left, len), 1);
+                let right = min(translate_neg_index(right, len), len);
+                if left > right {
+                    return vec![];
+                }
+                values
+                    .into_iter()
+                    .skip(left - 1)
+                    .take(right - left + 1)
+                    .collect()
+            }
+            Filter::On(ref separator) => values
+                .into_iter()
+                .flat_map(|value| split_on(value, separator))
+                .collect(),
+            Filter::NonEmpty => values
+                .into_iter()
+                .filter(|value| !value.is_empty())
+                .collect(),
+            Filter::Chunks(width) => values
+                .into_iter()
+                .flat_map(|value| chunks(value, width))
+                .collect(),
+            // nothing stays nothing, so that a split range without values is still left out
+            Filter::Join(ref separator) if !values.is_empty() => {
+                vec![Cow::Owned(values.join(separator))]
+            }
+            Filter::Join(_) => values,
+            _ => values.into_iter().map(|value| self.apply(value)).collect(),
+        }
+    }
+
+    fn apply<'a>(&self, value: Cow<'a, str>) -> Cow<'a, str> {
+        match self {
+            Filter::Upper => Cow::Owned(value.to_uppercase()),
+            Filter::Lower => Cow::Owned(value.to_lowercase()),
+            Filter::Trim => match value {
+                Cow::Borrowed(s) => Cow::Borrowed(s.trim()),
+                Cow::Owned(s) => Cow::Owned(s.trim().to_string()),
+            },
+            Filter::Basename => Cow::Owned(basename(&value).to_string()),
+            Filter::Dirname => Cow::Owned(dirname(&value).to_string()),
+            &Filter::Slice(start, end) => {
+                let len = value.chars().count();
+                let start = slice_index(start, len, 0);
+                let end = slice_index(end, len, len);
+                if start >= end {
+                    return Cow::Borrowed("");
+                }
+                Cow::Owned(value.chars().skip(start).take(end - start).collect())
+            }
+            Filter::Sub(pattern, replacement, true) => Cow::Owned(
+                pattern
+                    .replace_all(&value, replacement.as_str())
+                    .into_owned(),
+            ),
+            Filter::Sub(pattern, replacement, false) => {
+                Cow::Owned(pattern.replace(&value, replacement.as_str()).into_owned())
+            }
+            &Filter::Pad(width) => pad(value, width),
+            &Filter::UrlEncode(path) => url_encode(value, path),
+            Filter::UrlDecode => url_decode(value),
+            &Filter::Json(quoted) => json_escape(&value, quoted),
+            &Filter::Arithmetic(operator, operand) => arithmetic(value, operator, operand),
+            &Filter::Hash(algorithm, len) => hash(&value, algorithm, len),
+            &Filter::Bucket(buckets) => Cow::Owned(bucket(&value, buckets).to_string()),
+            Filter::Commas => commas(value),
+            &Filter::Round(decimals) => round(value, decimals),
+            Filter::Nth(..)
+            | Filter::On(_)
+            | Filter::Join(_)
+            | Filter::Chunks(_)
+            | Filter::NonEmpty => {
+                unreachable!("applied to all the values at once")
+            }
+        }
+    }
+}
+
+fn invalid_argument(spec: &str) -> String {
+    format!("invalid filter argument: {}", spec)
+}
+
+fn parse_width(arg: &str, spec: &str) -> Result<usize, String> {
+    match arg.parse() {
+        Ok(width) if width <= MAX_WIDTH => Ok(width),
+        Ok(_) => Err(format!(
+            "invalid filter argument: {}, at most {}",
+            spec, MAX_WIDTH
+        )),
+        Err(_) => Err(invalid_argument(spec)),
+    }
+}
+
+/// "2" selects the second value, "2..4" the second to the fourth, "-1" the last one
+fn parse_nth(arg: &str) -> Option<Filter> {
+    let parse = |s: &str, default: i64| {
+        if s.is_empty() {
+            Some(default)
+        } else {
+            s.parse().ok()
+        }
+    };
+
+    match arg.find("..") {
+        Some(pos) => Some(Filter::Nth(
+            parse(&arg[..pos], 1)?,
+            parse(&arg[(pos + 2)..], -1)?,
+        )),
+        None => {
+            let idx = arg.parse().ok()?;
+            Some(Filter::Nth(idx, idx))
+        }
+    }
+}
+
+/// "0:8" takes the first 8 characters, "-3:" the last 3, either end may be left out
+fn parse_slice(arg: &str) -> Option<Filter> {
+    let parse = |s: &str| {
+        if s.is_empty() {
+            Some(None)
+        } else {
+            s.parse().ok().map(Some)
+        }
+    };
+
+    let pos = arg.find(':')?;
+    Some(Filter::Slice(
+        parse(&arg[..pos])?,
+        parse(&arg[(pos + 1)..])?,
+    ))
+}
+
+fn parse_arithmetic(operator: Operator, arg: &str, spec: &str) -> Result<Filter, String> {
+    match (operator, arg.parse()) {
+        (Operator::Mod, Ok(0)) | (_, Err(_)) => Err(invalid_argument(spec)),
+        (operator, Ok(operand)) => Ok(Filter::Arithmetic(operator, operand)),
+    }
+}
+
+/// "sha256" gives the full digest, "sha256:8" its first 8 hex digits
+fn parse_hash(algorithm: Algorithm, arg: Option<&str>, spec: &str) -> Result<Filter, String> {
+    match arg.map(str::parse) {
+        None => Ok(Filter::Hash(algorithm, None)),
+        Some(Ok(len)) if len > 0 => Ok(Filter::Hash(algorithm, Some(len))),
+        Some(_) => Err(invalid_argument(spec)),
+    }
+}
+
+/// Like in sed: "/foo/bar/" replaces the first match of "foo", "/foo/bar/g" all of them. The
+/// first character is the delimiter, and the replacement may refer to groups like `$1`.
+///
+/// Returns the regex error if the pattern is invalid, `None` if the argument is malformed.
+fn parse_sub(arg: &str, ignore_case: bool) -> Result<Filter, Option<regex::Error>> {
+    let delimiter = arg.chars().next().ok_or(None)?;
+    let parts = arg[delimiter.len_utf8()..]
+        .split(delimiter)
+        .collect::<Vec<_>>();
+    let (pattern, replacement, all) = match parts[..] {
+        [pattern, replacement, ""] => (pattern, replacement, false),
+        [pattern, replacement, "g"] => (pattern, replacement, true),
+        _ => return Err(None),
+    };
+
+    let pattern = RegexBuilder::new(pattern)
+        .case_insensitive(ignore_case)
+        .build()
+        .map_err(Some)?;
+    Ok(Filter::Sub(pattern, replacement.to_string(), all))
+}
+
+/// Negative indexes count from the end, out of range ones are clamped
+fn slice_index(idx: Option<i64>, len: usize, default: usize) -> usize {
+    // an index that doesn't fit in a usize is past either end anyway
+    let clamp = |idx: u64| usize::try_from(idx).map_or(len, |idx| min(idx, len));
+    match idx {
+        None => default,
+        Some(idx) if idx < 0 => len - clamp(idx.unsigned_abs()),
+        Some(idx) => clamp(idx as u64),
+    }
+}
+
+fn translate_neg_index(idx: i64, len: usize) -> usize {
+    let idx = if idx < 0 { idx + len as i64 + 1 } else { idx };
+    max(0, idx) as usize
+}
+
+/// Left-pad integers with zeros, keeping the sign in front. Other values, and numbers already as
+/// wide as `width`, are left unchanged.
+fn pad(value: Cow<'_, str>, width: usize) -> Cow<'_, str> {
+    let (sign, digits) = match value.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", &value[..]),
+    };
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) || value.len() >= width {
+        return value;
+    }
+
+    let zeros = "0".repeat(width - value.len());
+    Cow::Owned(format!("{}{}{}", sign, zeros, digits))
+}
+
+/// Empty elements are kept, e.g. "a,,b" gives 3 values, `nonempty` drops them
+fn split_on<'a>(value: Cow<'a, str>, separator: &str) -> Vec<Cow<'a, str>> {
+    match value {
+        Cow::Borrowed(s) if separator.is_empty() => {
+            s.split_whitespace().map(Cow::Borrowed).collect()
+        }
+        Cow::Borrowed(s) => s.split(separator).map(Cow::Borrowed).collect(),
+        Cow::Owned(s) if separator.is_empty() => s
+            .split_whitespace()
+            .map(|part| Cow::Owned(part.to_string()))
+            .collect(),
+        Cow::Owned(s) => s
+            .split(separator)
+            .map(|part| Cow::Owned(part.to_string()))
+            .collect(),
+    }
+}
+
+/// Split into chunks of `width` characters, the last one may be shorter. An empty value has no
+/// chunks at all.
+fn chunks(value: Cow<'_, str>, width: usize) -> Vec<Cow<'_, str>> {
+    let bounds = value
+        .char_indices()
+        .map(|(pos, _)| pos)
+        .step_by(width)
+        .chain(std::iter::once(value.len()))
+        .collect::<Vec<_>>();
+    let chunk = |(start, end): (usize, usize)| match &value {
+        Cow::Borrowed(s) => Cow::Borrowed(&s[start..end]),
+        Cow::Owned(s) => Cow::Owned(s[start..end].to_string()),
+    };
+    bounds
+        .windows(2)
+        .map(|pair| chunk((pair[0], pair[1])))
+        .collect()
+}
+
+/// Percent-encode all the bytes but the unreserved characters of RFC 3986, so the value can be
+/// used as a query parameter. For paths, the separators and delimiters allowed in them are kept.
+fn url_encode(value: Cow<'_, str>, path: bool) -> Cow<'_, str> {
+    let is_kept = |b: u8| {
+        b.is_ascii_alphanumeric()
+            || b"-._~".contains(&b)
+            || (path && b"/:@!$&'()*+,;=".contains(&b))
+    };
+    if value.bytes().all(is_kept) {
+        return value;
+    }
+
+    let mut encoded = String::with_capacity(value.len() * 3);
+    for b in value.bytes() {
+        if is_kept(b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    Cow::Owned(encoded)
+}
+
+/// Decode the `%XX` escapes, malformed ones are kept as they are. `+` is not a space in URLs,
+/// only in forms, so it's kept too.
+fn url_decode(value: Cow<'_, str>) -> Cow<'_, str> {
+    if !value.contains('%') {
+        return value;
+    }
+
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get((i + 1)..(i + 3))
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    Cow::Owned(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+/// Values that are not integers, and results that overflow, are left unchanged. The modulo is
+/// never negative, so that it can be used to distribute the lines in buckets.
+fn arithmetic(value: Cow<'_, str>, operator: Operator, operand: i64) -> Cow<'_, str> {
+    let number = match value.trim().parse::<i64>() {
+        Ok(number) => number,
+        Err(_) => return value,
+    };
+    let result = match operator {
+        Operator::Add => number.checked_add(operand),
+        Operator::Sub => number.checked_sub(operand),
+        Operator::Mul => number.checked_mul(operand),
+        Operator::Mod => number.checked_rem_euclid(operand),
+    };
+    match result {
+        Some(result) => Cow::Owned(result.to_string()),
+        None => value,
+    }
+}
+
+/// Insert a `,` between the groups of 3 digits of the integer part, e.g. "-1234567.891" becomes
+/// "-1,234,567.891". Values that are not decimal numbers are left unchanged.
+fn commas(value: Cow<'_, str>) -> Cow<'_, str> {
+    let number = value.trim();
+    let (sign, number) = match number.strip_prefix('-') {
+        Some(number) => ("-", number),
+        None => ("", number),
+    };
+    let (integer, fraction) = match number.find('.') {
+        Some(pos) => number.split_at(pos),
+        None => (number, ""),
+    };
+    let is_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if !is_digits(integer) || !(fraction.is_empty() || is_digits(&fraction[1..])) {
+        return value;
+    }
+
+    let mut result = String::with_capacity(value.len() + integer.len() / 3);
+    result.push_str(sign);
+    for (i, digit) in integer.chars().enumerate() {
+        if i > 0 && (integer.len() - i) % 3 == 0 {
+            result.push(',');
+        }
+        result.push(digit);
+    }
+    result.push_str(fraction);
+    Cow::Owned(result)
+}
+
+/// Round to `decimals` digits after the `.`, e.g. "3.14159" becomes "3.14" with 2 decimals and
+/// "3" with none. Values that are not finite numbers are left unchanged.
+fn round(value: Cow<'_, str>, decimals: usize) -> Cow<'_, str> {
+    let number = match value.trim().parse::<f64>() {
+        Ok(number) if number.is_finite() => number,
+        _ => return value,
+    };
+    let rounded = format!("{:.*}", decimals, number);
+    // "-0.00" is not a number anybody wants to read
+    match rounded.strip_prefix('-') {
+        Some(zero) if zero.bytes().all(|b| b == b'0' || b == b'.') => Cow::Owned(zero.to_string()),
+        _ => Cow::Owned(rounded),
+    }
+}
+
+/// The lowercase hex digest of the UTF-8 bytes of the value
+fn hash(value: &str, algorithm: Algorithm, len: Option<usize>) -> Cow<'static, str> {
+    let digest = match algorithm {
+        Algorithm::Md5 => Md5::digest(value).to_vec(),
+        Algorithm::Sha1 => Sha1::digest(value).to_vec(),
+        Algorithm::Sha256 => Sha256::digest(value).to_vec(),
+    };
+    let mut hex = digest
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    if let Some(len) = len {
+        hex.truncate(len);
+    }
+    Cow::Owned(hex)
+}
+
+/// The first 8 bytes of the SHA-256 digest as a big-endian integer, modulo the number of
+/// buckets. Unlike the hasher of the standard library, it's the same on every run and platform.
+fn bucket(value: &str, buckets: u64) -> u64 {
+    let digest = Sha256::digest(value);
+    let mut prefix = [0; 8];
+    prefix.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(prefix) % buckets
+}
+
+/// Escape the value as a JSON string, without the quotes to insert it into a string of a template
+fn json_escape(value: &str, quoted: bool) -> Cow<'static, str> {
+    let escaped = serde_json::to_string(value).expect("strings are always valid JSON");
+    if quoted {
+        Cow::Owned(escaped)
+    } else {
+        Cow::Owned(escaped[1..(escaped.len() - 1)].to_string())
+    }
+}
+
+/// Like basename(1): the last component of the path, trailing slashes ignored
+fn basename(path: &str) -> &str {
+    match Path::new(path).file_name() {
+        Some(name) => name.to_str().unwrap_or(path),
+        None => path,
+    }
+}
+
+/// Like dirname(1): the path without its last component, "." if there is none
+fn dirname(path: &str) -> &str {
+    match Path::new(path).parent() {
+        Some(parent) if parent.as_os_str().is_empty() => ".",
+        Some(parent) => parent.to_str().unwrap_or(path),
+        None => path,
+    }
+}
@@ -1,237 +1,2777 @@
-use lazy_static::lazy_static;
-use regex::Regex;
+mod color;
+mod csv;
+mod dedupe;
+mod filter;
+mod preset;
+mod progress;
+mod rate;
+mod read;
+mod reorder;
+mod semaphore;
+mod shards;
+mod stats;
+
+use dedupe::Dedupe;
+use filter::Filter;
+use progress::Progress;
+use rate::RateLimit;
+use read::{read_record, trim_record_end};
+use regex::{Regex, RegexBuilder};
+use reorder::{Output, Reorder};
+use semaphore::Semaphore;
+use serde_json::Value;
+use shards::Shards;
+use stats::Stats;
 use std::{
     borrow::Cow,
-    cmp::max,
+    cmp::{max, min},
     collections::HashMap,
-    convert::From,
-    io::{self, BufRead},
-    process::{Command, Stdio},
-    sync::Arc,
+    fmt,
+    io::{self, BufRead, BufReader, BufWriter, IsTerminal, Read, Write},
+    os::unix::process::ExitStatusExt,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 use structopt::{clap::AppSettings, StructOpt};
 use threadpool::ThreadPool;
 
 const CONTEXT_KEY_LINENUM: &str = "LINENUM";
 const CONTEXT_KEY_LINENUM_SHORT: &str = "LN";
+const CONTEXT_KEY_LINE: &str = "LINE";
+const CONTEXT_KEY_LINE_SHORT: &str = "L";
+const CONTEXT_KEY_LINECOUNT: &str = "LINECOUNT";
+const CONTEXT_KEY_LINECOUNT_SHORT: &str = "NR";
+const CONTEXT_KEY_GROUP_COUNT: &str = "#";
+const CONTEXT_KEY_REPEAT: &str = "REPEAT";
+// the previous line only has its fields, not the special variables
+const SPECIAL_KEYS: &[&str] = &[
+    CONTEXT_KEY_LINENUM,
+    CONTEXT_KEY_LINENUM_SHORT,
+    CONTEXT_KEY_LINE,
+    CONTEXT_KEY_LINE_SHORT,
+    CONTEXT_KEY_LINECOUNT,
+    CONTEXT_KEY_LINECOUNT_SHORT,
+    CONTEXT_KEY_REPEAT,
+];
+const PREV_PREFIX: &str = "prev:";
+// `{match.2.1}` is the first group of the second match of the pattern
+const MATCH_PREFIX: &str = "match.";
+// of the variables of --env, and of the summary passed to --after
+const DEFAULT_ENV_PREFIX: &str = "RARGS_";
+
+// exit codes, following xargs where possible
+const EXIT_CHILD_FAILED: i32 = 123;
+const EXIT_CHILD_SIGNALED: i32 = 124;
+const EXIT_CANNOT_RUN: i32 = 127;
+const EXIT_INVALID_ARGS: i32 = 2;
+const EXIT_INVALID_INPUT: i32 = 1;
+const EXIT_INTERRUPTED: i32 = 130; // like shells, 128 + SIGINT
+
+// how long a timed out child may take to exit after SIGTERM before it gets SIGKILL
+const TIMEOUT_KILL_GRACE: Duration = Duration::from_secs(5);
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+const INTERRUPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// with --keep-order, how many commands per thread may wait for their output to be printed
+const KEEP_ORDER_CAPACITY_PER_THREAD: usize = 8;
+// by default, how many commands per thread may be queued or running before reading more input
+const QUEUE_SIZE_PER_THREAD: usize = 16;
+
+// set by the SIGINT handler, no more commands are started once it is set
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
 fn main() {
     let mut exit_code = 0;
 
     let options = Options::from_args();
-    let rargs = Arc::new(Rargs::new(&options));
+    if options.list_presets {
+        for (name, pattern) in preset::PRESETS {
+            println!("{}\t{}", name, pattern);
+        }
+        return;
+    }
+    let rargs = match Rargs::new(&options) {
+        Ok(rargs) => Arc::new(rargs),
+        Err(error) => {
+            eprintln!("rargs: {}", error);
+            std::process::exit(EXIT_INVALID_ARGS);
+        }
+    };
+    if options.check {
+        if !rargs.check() {
+            std::process::exit(EXIT_INVALID_ARGS);
+        }
+        return;
+    }
+
+    let shell = options.shell_path.as_deref().unwrap_or("sh");
+    if let Some(before) = options.before.as_ref().filter(|_| !options.dryrun) {
+        if !run_hook("--before", shell, before, &[]) {
+            std::process::exit(EXIT_CHILD_FAILED);
+        }
+    }
+
+    handle_interrupt();
+
+    if options.worker.is_some() {
+        eprintln!("rargs: --worker is deprecated, use --max-procs instead");
+    }
+    let num_threads = match options
+        .max_procs
+        .or(options.threads)
+        .or(options.worker)
+        .unwrap_or(1)
+    {
+        0 => num_cpus::get(),
+        num => num,
+    };
+
+    let pool = ThreadPool::new(num_threads);
+
+    // reading the input may block for good after an interrupt, e.g. on a terminal, so the running
+    // commands are waited for apart from the main loop
+    let interrupted_pool = pool.clone();
+    let interrupted_rargs = rargs.clone();
+    let after = options.after.clone().filter(|_| !options.dryrun);
+    let interrupted_shell = shell.to_string();
+    let env_prefix = options
+        .env_prefix
+        .clone()
+        .unwrap_or_else(|| DEFAULT_ENV_PREFIX.to_string());
+    thread::spawn(move || {
+        while !INTERRUPTED.load(Ordering::SeqCst) {
+            thread::sleep(INTERRUPT_POLL_INTERVAL);
+        }
+        interrupted_pool.join();
+        teardown(
+            &interrupted_rargs,
+            after.as_deref(),
+            &interrupted_shell,
+            &env_prefix,
+        );
+        std::process::exit(EXIT_INTERRUPTED);
+    });
+
+    let queue = Arc::new(Semaphore::new(
+        options
+            .queue_size
+            .unwrap_or(num_threads * QUEUE_SIZE_PER_THREAD),
+    ));
+
+    let reorder = if options.keep_order {
+        Some(Arc::new(Reorder::new(
+            num_threads * KEEP_ORDER_CAPACITY_PER_THREAD,
+        )))
+    } else {
+        None
+    };
+
+    // drawn over the output of the commands, so only on a terminal
+    let progress = if options.progress && io::stderr().is_terminal() {
+        Some(Arc::new(Progress::new()))
+    } else {
+        None
+    };
+
+    // more commands per job than fit in the window of --keep-order would wait for themselves
+    let dispatch_batch = match reorder.as_ref() {
+        Some(_) => min(
+            options.dispatch_batch,
+            num_threads * KEEP_ORDER_CAPACITY_PER_THREAD,
+        ),
+        None => options.dispatch_batch,
+    };
+    let mut seq = 0;
+    // the commands not handed to the threads yet, with --dispatch-batch
+    let mut pending = Vec::new();
+    // with --repeat, every batch is run several times, each run may go to another thread. An empty
+    // batch hands over the pending commands, before the pool is joined.
+    let mut dispatch = |lines: Vec<(String, i32, Option<String>)>| {
+        let submit = |jobs: Vec<Job>| {
+            let last_seq = match jobs.last() {
+                Some(&(_, _, last_seq)) => last_seq,
+                None => return,
+            };
+            if let Some(reorder) = reorder.as_ref() {
+                reorder.wait_for_slot(last_seq);
+            }
+
+            let rargs = rargs.clone();
+            let progress = progress.clone();
+            let reorder = reorder.clone();
+            let permit = queue.acquire();
+            pool.execute(move || {
+                let _permit = permit;
+                for (lines, repeat, cmd_seq) in jobs {
+                    // work queued before the first failure is dropped as well
+                    let output = if rargs.should_halt() {
+                        Output::default()
+                    } else {
+                        let batch = as_batch(&lines, repeat);
+                        // a bug is a failure of the batch, its slot with --keep-order is still done
+                        panic::catch_unwind(AssertUnwindSafe(|| rargs.execute_for_input(&batch)))
+                            .unwrap_or_else(|_| {
+                                eprintln!("rargs: internal error at {}", describe_lines(&batch));
+                                rargs.record_failure(EXIT_CANNOT_RUN);
+                                Output::default()
+                            })
+                    };
+                    if let Some(progress) = progress.as_ref() {
+                        progress.inc();
+                    }
+
+                    if let Some(reorder) = reorder.as_ref() {
+                        reorder.complete(cmd_seq, output, |output| {
+                            let mut stdout = rargs.stdout.lock().unwrap();
+                            let _ = stdout
+                                .write_all(&output.stdout)
+                                .and_then(|_| stdout.flush());
+                            let _ = rargs.stderr.lock().unwrap().write_all(&output.stderr);
+                        });
+                    }
+                }
+            });
+        };
+
+        if lines.is_empty() {
+            submit(std::mem::take(&mut pending));
+            return;
+        }
+        if options.emit_json {
+            rargs.emit_json(&as_batch(&lines, 1));
+            return;
+        }
+
+        let lines = Arc::new(lines);
+        for repeat in 1..=options.repeat {
+            if options.dryrun {
+                rargs.print_commands_to_be_executed(&as_batch(&lines, repeat));
+                if let Some(progress) = progress.as_ref() {
+                    progress.inc();
+                }
+                continue;
+            }
+
+            pending.push((lines.clone(), repeat, seq));
+            seq += 1;
+            if pending.len() >= dispatch_batch {
+                submit(std::mem::take(&mut pending));
+            }
+        }
+    };
+
+    let line_ending = match options.record_separator.as_ref() {
+        Some(separator) => unescape(separator).into_bytes(),
+        None if options.read0 => vec![b'\0'],
+        None => vec![b'\n'],
+    };
+    // room for the line ending, and the `\r` before a newline
+    let max_record_len = options
+        .max_line_length
+        .map(|max| max.saturating_add(line_ending.len() + 1));
+    // None once the line number overflowed
+    let mut next_line_num = Some(options.startnum);
+    let mut batch = Vec::new();
+    // only kept if a template refers to the fields of the previous line
+    let mut prev_line: Option<String> = None;
+    let mut group_key = None;
+    // across all the inputs
+    let mut dedupe = if options.dedupe || options.dedupe_field.is_some() {
+        Some(Dedupe::new(options.dedupe_window))
+    } else {
+        None
+    };
+    let mut remaining = options.limit;
+
+    // stdin is read if no input file is given
+    let inputs = if options.input_files.is_empty() {
+        vec![None]
+    } else {
+        options
+            .input_files
+            .iter()
+            .map(|path| Some(path.as_str()))
+            .collect()
+    };
+
+    let readers = inputs.into_iter().map(open_input);
+
+    // with --count-first, the batches and headers are held until all of the input is read, so that
+    // {NR} and the total of --progress count the lines the commands are run for
+    let mut held = if options.count_first {
+        Some(Vec::new())
+    } else {
+        None
+    };
+    let mut hand_over = |handover: Handover| {
+        let handovers = match held.as_mut() {
+            Some(handovers) if !matches!(handover, Handover::End) => {
+                // the lines after a header are read with its names before its commands run
+                if let Handover::Header(line) = &handover {
+                    rargs.set_header(line);
+                }
+                handovers.push(handover);
+                return;
+            }
+            Some(_) => {
+                let mut handovers = held.take().unwrap_or_default();
+                let (mut line_count, mut batches) = (0, 0);
+                for handover in &handovers {
+                    match handover {
+                        Handover::Batch(lines) => {
+                            line_count += lines.len();
+                            batches += 1;
+                        }
+                        // counted like it's numbered by {LN}
+                        Handover::Header(_) => line_count += 1,
+                        Handover::End => {}
+                    }
+                }
+                let _ = rargs.line_count.set(line_count);
+                if let Some(progress) = progress.as_ref() {
+                    progress.set_total(batches * options.repeat);
+                }
+                handovers.push(handover);
+                handovers
+            }
+            None => vec![handover],
+        };
+        for handover in handovers {
+            match handover {
+                Handover::Batch(lines) => dispatch(lines),
+                // the lines of the previous input are expanded with its own header
+                Handover::Header(line) => {
+                    dispatch(Vec::new());
+                    pool.join();
+                    rargs.set_header(&line);
+                }
+                Handover::End => dispatch(Vec::new()),
+            }
+        }
+    };
+    let progress_handle = progress.as_ref().map(|progress| progress.start());
+
+    'inputs: for reader in readers {
+        let mut reader = match reader {
+            Ok(reader) => reader,
+            Err(error) => {
+                eprintln!("rargs: {}", error);
+                exit_code = EXIT_INVALID_INPUT;
+                continue;
+            }
+        };
+
+        if options.reset_linenum {
+            next_line_num = Some(options.startnum);
+        }
+        let mut header_pending = options.header;
+        let mut skip_pending = options.skip;
+
+        // reused for every record, only the lines that are dispatched are copied into a String
+        let mut buffer = Vec::with_capacity(1024);
+        loop {
+            if rargs.should_halt() || remaining == Some(0) {
+                break 'inputs;
+            }
+
+            buffer.clear();
+            match read_record(&mut reader, &line_ending, &mut buffer, max_record_len) {
+                Ok(n) => {
+                    if n == 0 {
+                        break;
+                    }
+                    let discarded = n > buffer.len();
+
+                    trim_record_end(
+                        &mut buffer,
+                        &line_ending,
+                        options.record_separator.is_some(),
+                    );
+
+                    if skip_pending > 0 {
+                        skip_pending -= 1;
+                        if options.number_skipped {
+                            next_line_num = next_line_num.and_then(|n| n.checked_add(options.step));
+                        }
+                        continue;
+                    }
+                    // the header is never filtered out, the lines are checked before they are
+                    // decoded so that they don't get a line number
+                    if !header_pending && !rargs.is_selected(&String::from_utf8_lossy(&buffer)) {
+                        if options.number_skipped {
+                            next_line_num = next_line_num.and_then(|n| n.checked_add(options.step));
+                        }
+                        continue;
+                    }
+
+                    // execute command on line, or on a batch of lines with --max-args
+                    let line_num = match next_line_num {
+                        Some(line_num) => line_num,
+                        None => {
+                            eprintln!(
+                                "rargs: line number out of range, skip the rest of the input"
+                            );
+                            exit_code = EXIT_INVALID_INPUT;
+                            break 'inputs;
+                        }
+                    };
+                    next_line_num = line_num.checked_add(options.step);
+                    if let Some(max) = options.max_line_length {
+                        if discarded || buffer.len() > max {
+                            match options.on_long_line.as_str() {
+                                "truncate" => {
+                                    eprintln!(
+                                        "rargs: truncate line {}: longer than {} bytes",
+                                        line_num, max
+                                    );
+                                    truncate_utf8(&mut buffer, max);
+                                }
+                                "error" => {
+                                    eprintln!(
+                                        "rargs: line {} is longer than {} bytes, skip the rest of the input",
+                                        line_num, max
+                                    );
+                                    exit_code = EXIT_INVALID_INPUT;
+                                    break 'inputs;
+                                }
+                                _ => {
+                                    eprintln!(
+                                        "rargs: skip line {}: longer than {} bytes",
+                                        line_num, max
+                                    );
+                                    continue;
+                                }
+                            }
+                        }
+                    }
+                    // empty lines keep their line number, like the other skipped lines
+                    if buffer.is_empty() && options.on_empty != "process" {
+                        if options.on_empty == "separator" && !batch.is_empty() {
+                            hand_over(Handover::Batch(std::mem::take(&mut batch)));
+                            group_key = None;
+                        }
+                        continue;
+                    }
+                    let line = match std::str::from_utf8(&buffer) {
+                        Ok(line) => line.to_string(),
+                        Err(_) if options.lossy => String::from_utf8_lossy(&buffer).into_owned(),
+                        Err(_) => {
+                            eprintln!("rargs: skip line {}: invalid UTF-8", line_num);
+                            continue;
+                        }
+                    };
+                    // after decoding, so the replacement characters of --lossy count as well
+                    let non_ascii = if options.ascii_only {
+                        line.bytes().position(|b| !b.is_ascii())
+                    } else {
+                        None
+                    };
+                    if let Some(pos) = non_ascii {
+                        if options.on_non_ascii == "error" {
+                            eprintln!(
+                                "rargs: line {} has a non-ASCII character at byte {}, skip the rest of the input",
+                                line_num, pos
+                            );
+                            exit_code = EXIT_INVALID_INPUT;
+                            break 'inputs;
+                        }
+                        eprintln!(
+                            "rargs: skip line {}: non-ASCII character at byte {}",
+                            line_num, pos
+                        );
+                        continue;
+                    }
+                    if options.json {
+                        if let Err(error) = serde_json::from_str::<Value>(&line) {
+                            eprintln!("rargs: skip line {}: invalid JSON: {}", line_num, error);
+                            continue;
+                        }
+                    }
+                    if !options.json && !options.csv && rargs.select_pattern(&line).is_none() {
+                        eprintln!("rargs: skip line {}: no pattern matches", line_num);
+                        continue;
+                    }
+                    if header_pending {
+                        header_pending = false;
+                        if !batch.is_empty() {
+                            hand_over(Handover::Batch(std::mem::take(&mut batch)));
+                        }
+                        hand_over(Handover::Header(line));
+                        continue;
+                    }
+                    if let Some(dedupe) = dedupe.as_mut() {
+                        let key = rargs.dedupe_key(&Line {
+                            text: &line,
+                            num: line_num,
+                            prev: None,
+                            repeat: 1,
+                        });
+                        if !dedupe.insert(key) {
+                            continue;
+                        }
+                    }
+                    // the command still runs, with the fields unmatched or of --fallback-split
+                    if options.print_unmatched && !rargs.is_matched(&line) {
+                        eprintln!("rargs: unmatched line {}: {}", line_num, line);
+                    }
+                    let prev = if rargs.uses_prev {
+                        prev_line.replace(line.clone())
+                    } else {
+                        None
+                    };
+                    let key = rargs.group_key(&Line {
+                        text: &line,
+                        num: line_num,
+                        prev: prev.as_deref(),
+                        repeat: 1,
+                    });
+                    if let Some(key) = key {
+                        if !batch.is_empty() && group_key.as_ref().is_some_and(|k| *k != key) {
+                            hand_over(Handover::Batch(std::mem::take(&mut batch)));
+                        }
+                        group_key = Some(key);
+                    }
+                    batch.push((line, line_num, prev));
+
+                    let batch_full = match options.max_args {
+                        _ if rargs.group_by.is_some() => false,
+                        None => true,
+                        Some(0) => false,
+                        Some(n) => batch.len() >= n,
+                    };
+                    if batch_full {
+                        hand_over(Handover::Batch(std::mem::take(&mut batch)));
+                    }
+
+                    // the last batch is dispatched with the rest after the loop
+                    if let Some(n) = remaining.as_mut() {
+                        *n -= 1;
+                        if *n == 0 {
+                            break 'inputs;
+                        }
+                    }
+                }
+                Err(_err) => {
+                    // failed to read the input, skip the rest of it.
+                    exit_code = EXIT_INVALID_INPUT;
+                    break;
+                }
+            }
+        }
+    }
+
+    // the last batch may hold fewer than `max_args` lines
+    if !batch.is_empty() {
+        hand_over(Handover::Batch(batch));
+    }
+    hand_over(Handover::End);
+
+    pool.join();
+    if let (Some(progress), Some(handle)) = (progress.as_ref(), progress_handle) {
+        progress.finish(handle);
+    }
+    let after = options.after.as_deref().filter(|_| !options.dryrun);
+    let prefix = options.env_prefix.as_deref().unwrap_or(DEFAULT_ENV_PREFIX);
+    exit_code = max(exit_code, teardown(&rargs, after, shell, prefix));
+
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        std::process::exit(EXIT_INTERRUPTED);
+    }
+    if let Some(&code) = rargs.first_failure.get().filter(|_| options.fail_fast) {
+        std::process::exit(code);
+    }
+    std::process::exit(max(exit_code, rargs.exit_code.load(Ordering::SeqCst)));
+}
+
+/// Flush the output, print the summaries and run `--after`, once, at the end of the input or on
+/// an interrupt, whichever comes first. The other caller waits for it to finish. Returns the exit
+/// code of the teardown itself, 0 unless flushing a shard or `--after` failed.
+fn teardown(rargs: &Rargs, after: Option<&str>, shell: &str, prefix: &str) -> i32 {
+    static DONE: OnceLock<i32> = OnceLock::new();
+    *DONE.get_or_init(|| {
+        let mut exit_code = 0;
+        let _ = rargs.stdout.lock().unwrap().flush();
+        if let Err(error) = rargs.shards.flush() {
+            eprintln!("rargs: {}", error);
+            exit_code = EXIT_CANNOT_RUN;
+        }
+        if let Some(stats) = rargs.stats.as_ref() {
+            if rargs.stats_json {
+                eprintln!("{}", stats.summary_json());
+            } else {
+                eprintln!("{}", stats.summary());
+            }
+        }
+
+        let failures = rargs.failures.load(Ordering::SeqCst);
+        if failures > 0 {
+            eprintln!("rargs: {} command(s) failed", failures);
+        }
+        // also after failures and interrupts, to tear down what --before set up
+        if let Some(after) = after {
+            let summary = [
+                (
+                    format!("{}COMMANDS", prefix),
+                    rargs.commands.load(Ordering::SeqCst).to_string(),
+                ),
+                (format!("{}FAILURES", prefix), failures.to_string()),
+            ];
+            if !run_hook("--after", shell, after, &summary) {
+                exit_code = max(exit_code, EXIT_CHILD_FAILED);
+            }
+        }
+        exit_code
+    })
+}
+
+/// Run the script of `--before` or `--after` with `<shell> -c`, it shares the stdout and stderr
+/// of rargs but not its stdin, which is the input. Returns false if it failed.
+fn run_hook(flag: &str, shell: &str, script: &str, env: &[(String, String)]) -> bool {
+    let status = Command::new(shell)
+        .arg("-c")
+        .arg(script)
+        .envs(env.iter().map(|(name, value)| (name, value)))
+        .stdin(Stdio::null())
+        .status();
+    match status {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            eprintln!("rargs: {} failed: {}", flag, status);
+            false
+        }
+        Err(error) => {
+            eprintln!("rargs: {}: {}: {}", flag, shell, error);
+            false
+        }
+    }
+}
+
+/// Truncate to at most `max` bytes, without splitting a character
+fn truncate_utf8(buffer: &mut Vec<u8>, max: usize) {
+    let mut len = min(max, buffer.len());
+    // continuation bytes look like 0b10xxxxxx
+    while len > 0 && len < buffer.len() && buffer[len] & 0xC0 == 0x80 {
+        len -= 1;
+    }
+    buffer.truncate(len);
+}
+
+/// What the read loop hands over to be run
+enum Handover {
+    Batch(Vec<(String, i32, Option<String>)>),
+    /// the header of the next input, after the commands of the previous one are done
+    Header(String),
+    End,
+}
+
+/// The lines of a command, which run of them with --repeat, and its sequence number
+type Job = (Arc<Vec<(String, i32, Option<String>)>>, usize, usize);
+
+fn as_batch(lines: &[(String, i32, Option<String>)], repeat: usize) -> Vec<Line<'_>> {
+    lines
+        .iter()
+        .map(|(line, line_num, prev)| Line {
+            text: line,
+            num: *line_num,
+            prev: prev.as_deref(),
+            repeat,
+        })
+        .collect()
+}
+
+/// A line of the input along with the state its fields are expanded in
+#[derive(Clone, Copy)]
+struct Line<'a> {
+    text: &'a str,
+    num: i32,
+    prev: Option<&'a str>, // only kept if a template refers to it
+    repeat: usize,         // which run of the command with --repeat, from 1
+}
+
+/// The regexes recognizing fields in the templates, built from the placeholder delimiters
+#[derive(Debug)]
+struct FieldSyntax {
+    open: String,
+    close: String,
+    cmd: Regex,
+    conditional: Regex,
+    nested_default: Regex,
+    unclosed: Regex, // a default or conditional left open at the end of a literal
+    named: Regex,
+    env: Regex,
+    single: Regex,
+    range: Regex,
+    split_range: Regex,
+    ignore_case: bool, // for the regexes of the filters
+}
+
+impl FieldSyntax {
+    /// Regex metacharacters in the delimiters are escaped. The content of a field may not
+    /// contain any character of the delimiters.
+    fn new(open: &str, close: &str) -> Self {
+        let o = regex::escape(open);
+        let c = regex::escape(close);
+        let chars = open
+            .chars()
+            .chain(close.chars())
+            .map(|ch| regex::escape(&ch.to_string()))
+            .collect::<String>();
+
+        let build = |re: String| Regex::new(&re).expect("field regex is invalid");
+        FieldSyntax {
+            open: open.to_string(),
+            close: close.to_string(),
+            // doubled delimiters are escapes, conditionals and defaults may contain fields, other
+            // fields can't be nested
+            cmd: build(format!(
+                r"{o}{o}|{c}{c}|{o}[[:space:]]*[[:word:]]+\?(?:[^{chars}]|{o}[^{chars}]*{c})*{c}|{o}[^{chars}]*?:-(?:[^{chars}]|{o}[^{chars}]*{c})*{c}|{o}[[:space:]]*[^{chars}]*[[:space:]]*{c}"
+            )),
+            conditional: build(format!(
+                r"(?s)^{o}[[:space:]]*(?P<name>[[:word:]]+)\?(?P<body>.*){c}$"
+            )),
+            nested_default: build(format!(
+                r"(?s)^{o}(?P<field>[^{chars}]*?):-(?P<default>.*{o}.*){c}$"
+            )),
+            unclosed: build(format!(
+                r"{o}(?:[^{chars}]*?:-|[[:space:]]*[[:word:]]+\?)[^{chars}]*$"
+            )),
+            named: build(format!(
+                r"^{o}[[:space:]]*(?P<name>#|@[[:word:]]+|prev:[[:word:]]+|(?:[[:word:]]+(?:\.[[:word:]]+)*)?)(?::-(?P<default>[^{chars}]*?))?[[:space:]]*{c}$"
+            )),
+            env: build(format!(
+                r"^{o}[[:space:]]*\$(?P<name>[[:word:]]+)(?::-(?P<default>[^{chars}]*?))?[[:space:]]*{c}$"
+            )),
+            single: build(format!(
+                r"^{o}[[:space:]]*(?P<num>-?\d+)(?::-(?P<default>[^{chars}]*?))?[[:space:]]*{c}$"
+            )),
+            range: build(format!(
+                r"^{o}(?P<left>-?\d*)?\.\.(?P<right>-?\d*)?(?::(?P<sep>.*))?{c}$"
+            )),
+            split_range: build(format!(r"^{o}(?P<left>-?\d*)?\.\.\.(?P<right>-?\d*)?{c}$")),
+            ignore_case: false,
+        }
+    }
+
+    fn ignore_case(mut self, ignore_case: bool) -> Self {
+        self.ignore_case = ignore_case;
+        self
+    }
+
+    /// Parse the value of `--replace-str`: the open and close delimiters separated by a space
+    fn from_replace_str(replace_str: &str) -> Result<Self, String> {
+        match replace_str.split_whitespace().collect::<Vec<_>>()[..] {
+            [open, close] => Ok(FieldSyntax::new(open, close)),
+            _ => Err(format!(
+                "invalid replace-str: {:?}, expect open and close delimiters separated by a space",
+                replace_str
+            )),
+        }
+    }
+}
+
+impl Default for FieldSyntax {
+    fn default() -> Self {
+        FieldSyntax::new("{", "}")
+    }
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "Rargs", about = "Xargs with pattern matching")]
+#[structopt(settings = &[AppSettings::TrailingVarArg])]
+struct Options {
+    #[structopt(
+        long = "read0",
+        short = "0",
+        help = "Read input delimited by ASCII NUL(\\0) characters"
+    )]
+    read0: bool,
+
+    #[structopt(
+        long = "record-separator",
+        conflicts_with = "read0",
+        help = "Read input delimited by this string instead of newlines, e.g. '\\n--\\n'"
+    )]
+    record_separator: Option<String>,
+
+    #[structopt(
+        long = "max-procs",
+        short = "P",
+        help = "Number of commands to run at the same time, 0 for the number of CPUs [default: 1]"
+    )]
+    max_procs: Option<usize>,
+
+    #[structopt(long = "worker", short = "w", help = "Deprecated. Same as --max-procs")]
+    worker: Option<usize>,
+
+    #[structopt(long = "threads", short = "j", help = "Same as --max-procs")]
+    threads: Option<usize>,
+
+    #[structopt(
+        long = "queue-size",
+        help = "Number of commands that may be queued or running before more input is read [default: 16 per thread]"
+    )]
+    queue_size: Option<usize>,
+
+    #[structopt(
+        long = "dispatch-batch",
+        default_value = "1",
+        help = "Number of commands handed to a thread at once, to cut the overhead of cheap commands on many lines"
+    )]
+    dispatch_batch: usize,
+
+    #[structopt(
+        long = "json",
+        conflicts_with_all = &["pattern", "preset", "delimiter", "csv"],
+        help = "Read input as JSON lines, the fields are the keys of each object"
+    )]
+    json: bool,
+
+    #[structopt(
+        long = "csv",
+        conflicts_with_all = &["pattern", "preset", "delimiter"],
+        help = "Read input as CSV, the fields are the columns of each line"
+    )]
+    csv: bool,
+
+    #[structopt(
+        long = "progress",
+        help = "Show the progress on stderr, as a bar with --count-first"
+    )]
+    progress: bool,
+
+    #[structopt(
+        long = "stats",
+        help = "Print the count and the durations of the commands to stderr when all are done"
+    )]
+    stats: bool,
+
+    #[structopt(long = "stats-json", help = "Same as --stats, as a JSON object")]
+    stats_json: bool,
+
+    #[structopt(
+        long = "count-first",
+        help = "Read all the input before running any command, to count the lines for {LINECOUNT}"
+    )]
+    count_first: bool,
+
+    #[structopt(
+        long = "header",
+        conflicts_with = "json",
+        help = "Use the first line of each input as the names of the numbered fields"
+    )]
+    header: bool,
+
+    #[structopt(
+        long = "skip",
+        default_value = "0",
+        help = "Ignore the first <skip> lines of each input, before the header"
+    )]
+    skip: usize,
+
+    #[structopt(
+        long = "number-skipped",
+        help = "Advance the line number for the lines ignored by --skip, --match and --no-match"
+    )]
+    number_skipped: bool,
+
+    #[structopt(
+        long = "match",
+        help = "Only process the lines matching this regex, like grep, the others are ignored like --skip"
+    )]
+    select: Option<String>,
+
+    #[structopt(
+        long = "no-match",
+        help = "Ignore the lines matching this regex, like grep -v"
+    )]
+    reject: Option<String>,
+
+    #[structopt(
+        long = "limit",
+        help = "Stop after <limit> lines, not counting the lines ignored by --skip or --header"
+    )]
+    limit: Option<usize>,
+
+    #[structopt(
+        long = "csv-delimiter",
+        default_value = ",",
+        help = "The delimiter between the columns of CSV input"
+    )]
+    csv_delimiter: char,
+
+    #[structopt(
+        long = "pattern",
+        short = "p",
+        number_of_values = 1,
+        help = "regex pattern that captures the input, multiple ones are tried in order"
+    )]
+    pattern: Vec<String>,
+
+    #[structopt(
+        long = "preset",
+        number_of_values = 1,
+        help = "Named pattern for a common format like nginx, tried after the patterns of -p"
+    )]
+    preset: Vec<String>,
+
+    #[structopt(
+        long = "list-presets",
+        help = "Print the names and patterns of the presets"
+    )]
+    list_presets: bool,
+
+    #[structopt(
+        long = "check",
+        help = "Print the fields found in the templates and exit without reading the input"
+    )]
+    check: bool,
+
+    #[structopt(
+        long = "ignore-case",
+        short = "i",
+        help = "Match the patterns, the delimiter and the regexes of `sub` filters case-insensitively"
+    )]
+    ignore_case: bool,
+
+    #[structopt(
+        long = "dotall",
+        help = "Let `.` in the patterns match newlines too, for records spanning multiple lines"
+    )]
+    dotall: bool,
+
+    #[structopt(
+        long = "multiline",
+        help = "Let `^` and `$` in the patterns match at the start and end of every line of a record"
+    )]
+    multiline: bool,
+
+    #[structopt(
+        long = "single-match",
+        help = "Only capture the groups of the first match of the pattern"
+    )]
+    single_match: bool,
+
+    #[structopt(
+        long = "trim",
+        help = "Strip whitespace around the value of every captured group, but not {0}"
+    )]
+    trim: bool,
+
+    #[structopt(
+        long = "trim-chars",
+        help = "Strip these characters around the value of every captured group, implies --trim"
+    )]
+    trim_chars: Option<String>,
+
+    #[structopt(
+        long = "fallback-split",
+        help = "Split lines matching none of multiple patterns on whitespace instead of skipping"
+    )]
+    fallback_split: bool,
+
+    #[structopt(
+        long = "print-unmatched",
+        help = "Print the lines that no pattern matches to stderr, to debug the pattern"
+    )]
+    print_unmatched: bool,
+
+    #[structopt(
+        long = "with-fields",
+        help = "Number the fields split on whitespace, and take only the named groups from the pattern"
+    )]
+    with_fields: bool,
+
+    #[structopt(
+        long = "env",
+        help = "Export the fields to the environment of the command, e.g. RARGS_1 and RARGS_name"
+    )]
+    env: bool,
+
+    #[structopt(
+        long = "env-prefix",
+        help = "Export the fields to the environment of the command with this prefix, implies --env"
+    )]
+    env_prefix: Option<String>,
+
+    #[structopt(
+        long = "separator",
+        short = "s",
+        default_value = " ",
+        help = "separator for ranged fields"
+    )]
+    separator: String,
+
+    #[structopt(
+        long = "output-separator",
+        alias = "field-separator-output",
+        default_value = " ",
+        help = "separator for the values that filters like `on` leave in a field, independent of -s"
+    )]
+    output_separator: String,
+
+    #[structopt(
+        long = "startnum",
+        short = "n",
+        default_value = "1",
+        help = "start value for line number"
+    )]
+    startnum: i32,
+
+    #[structopt(
+        long = "step",
+        default_value = "1",
+        allow_hyphen_values = true,
+        help = "increment of the line number, negative to count down"
+    )]
+    step: i32,
+
+    #[structopt(
+        long = "input-file",
+        short = "a",
+        number_of_values = 1,
+        help = "Read input from the file instead of stdin, can be given multiple times"
+    )]
+    input_files: Vec<String>,
+
+    #[structopt(
+        long = "reset-linenum",
+        help = "Restart the line number at each input file"
+    )]
+    reset_linenum: bool,
+
+    #[structopt(
+        long = "delimiter",
+        short = "d",
+        conflicts_with_all = &["pattern", "preset"],
+        help = "regex pattern used as delimiter (conflict with pattern)"
+    )]
+    delimiter: Option<String>,
+
+    #[structopt(
+        long = "fixed-delimiter",
+        short = "F",
+        conflicts_with_all = &["pattern", "preset", "delimiter", "json", "csv"],
+        help = "Literal string used as delimiter, unlike --delimiter it's not a regex"
+    )]
+    fixed_delimiter: Option<String>,
+
+    #[structopt(
+        long = "delimiter-chars",
+        conflicts_with_all = &["pattern", "preset", "delimiter", "fixed-delimiter", "json", "csv"],
+        help = "Split on runs of any of these characters, like the FS of awk, e.g. ',;|'"
+    )]
+    delimiter_chars: Option<String>,
+
+    #[structopt(
+        long = "keep-empty-fields",
+        help = "Keep every field between two delimiters, and unmatched groups, as empty fields"
+    )]
+    keep_empty_fields: bool,
+
+    #[structopt(
+        long = "min-fields",
+        help = "Pad the numbered fields of short lines with empty fields up to <min-fields>"
+    )]
+    min_fields: Option<usize>,
+
+    #[structopt(
+        long = "ci-names",
+        help = "Look up the named groups and the header fields ignoring case, e.g. {Email} as {email}"
+    )]
+    ci_names: bool,
+
+    #[structopt(
+        long = "each",
+        help = "Join the text around a split range to each of its elements"
+    )]
+    each: bool,
+
+    #[structopt(
+        long = "lossy",
+        help = "Replace invalid UTF-8 in the input instead of skipping the line"
+    )]
+    lossy: bool,
+
+    #[structopt(
+        long = "dry-run",
+        short = "e",
+        help = "Print the commands to be executed without actually execute"
+    )]
+    dryrun: bool,
+
+    #[structopt(
+        long = "emit-json",
+        conflicts_with_all = &["cmd-and-args", "command-file", "json", "dryrun"],
+        help = "Print the fields of each line as a JSON object instead of running a command"
+    )]
+    emit_json: bool,
+
+    #[structopt(
+        long = "prompt",
+        conflicts_with_all = &["dryrun", "max-procs", "threads", "worker"],
+        help = "Ask on the terminal before running each command, like xargs -p"
+    )]
+    prompt: bool,
+
+    #[structopt(
+        long = "null-output",
+        help = "In dry-run, print each argument terminated by NUL(\\0) and each command by an extra NUL"
+    )]
+    null_output: bool,
+
+    #[structopt(
+        long = "quoted-output",
+        conflicts_with = "null-output",
+        help = "In dry-run, print the commands shell-quoted with the line number as a comment"
+    )]
+    quoted_output: bool,
+
+    #[structopt(
+        long = "verbose",
+        short = "t",
+        help = "Print each command shell-quoted to stderr before running it, like `set -x`"
+    )]
+    verbose: bool,
+
+    #[structopt(
+        long = "tee",
+        conflicts_with = "dryrun",
+        help = "Print each command shell-quoted to stdout before running it, like --quoted-output"
+    )]
+    tee: bool,
+
+    #[structopt(
+        long = "output0",
+        conflicts_with = "null-output",
+        help = "Terminate the commands printed by --dry-run, --verbose and --tee with NUL(\\0) instead of newlines"
+    )]
+    output0: bool,
+
+    #[structopt(
+        long = "color",
+        default_value = "auto",
+        possible_values = &["auto", "always", "never"],
+        help = "In dry-run, print each line above its command with the captured groups colored"
+    )]
+    color: String,
+
+    #[structopt(
+        long = "halt-on-error",
+        help = "Stop running new commands after the first command failed"
+    )]
+    halt_on_error: bool,
+
+    #[structopt(
+        long = "fail-fast",
+        help = "Like --halt-on-error, but also stop the running commands, and exit with the code of the first failure"
+    )]
+    fail_fast: bool,
+
+    #[structopt(
+        long = "no-run-if-empty",
+        short = "r",
+        help = "Don't run the command if the fields of the input are all empty"
+    )]
+    no_run_if_empty: bool,
+
+    #[structopt(
+        long = "quote",
+        help = "Shell-quote the content substituted into the arguments"
+    )]
+    quote: bool,
+
+    #[structopt(
+        long = "exit-on-missing-field",
+        help = "Treat fields missing in the input as errors and skip the command"
+    )]
+    strict: bool,
+
+    #[structopt(
+        long = "timeout",
+        help = "Kill commands that run longer than <timeout> seconds"
+    )]
+    timeout: Option<u64>,
+
+    #[structopt(
+        long = "keep-order",
+        help = "Print the output of the commands in the order of the input"
+    )]
+    keep_order: bool,
+
+    #[structopt(
+        long = "rate",
+        alias = "rate-limit",
+        help = "Start at most <rate> commands per second across all the threads, e.g. 5 or 0.5"
+    )]
+    rate: Option<f64>,
+
+    #[structopt(
+        long = "rate-burst",
+        help = "How many commands --rate lets start at once after a pause [default: 1]"
+    )]
+    rate_burst: Option<u32>,
+
+    #[structopt(
+        long = "retries",
+        default_value = "0",
+        help = "Run failed commands again up to <retries> times"
+    )]
+    retries: usize,
+
+    #[structopt(
+        long = "retry-delay",
+        default_value = "1",
+        help = "Seconds to wait before the first retry, doubled for every following one"
+    )]
+    retry_delay: u64,
+
+    #[structopt(
+        long = "retry-on-codes",
+        use_delimiter = true,
+        help = "Only retry commands that exit with one of these codes, e.g. 1,7"
+    )]
+    retry_on_codes: Vec<i32>,
+
+    #[structopt(
+        long = "repeat",
+        default_value = "1",
+        help = "Run the command <repeat> times for each line, the run is available as {REPEAT}"
+    )]
+    repeat: usize,
+
+    #[structopt(
+        long = "max-args",
+        help = "Run the command once per <max-args> lines, 0 for all lines in one invocation"
+    )]
+    max_args: Option<usize>,
+
+    #[structopt(
+        long = "group-by",
+        conflicts_with = "max-args",
+        help = "Run the command once per run of consecutive lines whose <group-by> template expands to the same key"
+    )]
+    group_by: Option<String>,
+
+    #[structopt(
+        long = "dedupe",
+        help = "Skip the lines that are the same as an earlier line"
+    )]
+    dedupe: bool,
+
+    #[structopt(
+        long = "dedupe-field",
+        help = "Skip the lines whose field <dedupe-field> is the same as in an earlier line, e.g. 2 or email"
+    )]
+    dedupe_field: Option<String>,
+
+    #[structopt(
+        long = "dedupe-window",
+        help = "Only remember the last <dedupe-window> distinct lines or fields to skip the duplicates of"
+    )]
+    dedupe_window: Option<usize>,
+
+    #[structopt(
+        long = "on-empty",
+        default_value = "process",
+        possible_values = &["process", "skip", "separator"],
+        help = "Run the command for empty lines, skip them, or end the current batch or group at them"
+    )]
+    on_empty: String,
+
+    #[structopt(
+        long = "max-line-length",
+        help = "Longest line in bytes that is read in full, see --on-long-line for the longer ones"
+    )]
+    max_line_length: Option<usize>,
+
+    #[structopt(
+        long = "on-long-line",
+        default_value = "skip",
+        possible_values = &["skip", "truncate", "error"],
+        help = "Skip the lines longer than --max-line-length, truncate them, or stop reading the input"
+    )]
+    on_long_line: String,
+
+    #[structopt(
+        long = "ascii-only",
+        help = "Reject the input lines with non-ASCII characters, see --on-non-ascii"
+    )]
+    ascii_only: bool,
+
+    #[structopt(
+        long = "on-non-ascii",
+        default_value = "skip",
+        possible_values = &["skip", "error"],
+        help = "With --ascii-only, skip the lines with non-ASCII characters or stop reading the input"
+    )]
+    on_non_ascii: String,
+
+    #[structopt(
+        long = "append-args",
+        help = "Append the whole input line to the arguments of the command, as a single argument"
+    )]
+    append_args: bool,
+
+    #[structopt(
+        long = "split-appended",
+        requires = "append-args",
+        help = "Split the line appended by --append-args on whitespace into multiple arguments"
+    )]
+    split_appended: bool,
+
+    #[structopt(
+        long = "shell",
+        help = "Run the command with `sh -c`, allowing pipes and redirections"
+    )]
+    shell: bool,
+
+    #[structopt(
+        long = "shell-path",
+        help = "Run the command with `<shell-path> -c`, implies --shell"
+    )]
+    shell_path: Option<String>,
+
+    #[structopt(
+        long = "before",
+        help = "Run this shell command once before reading the input, the input isn't processed if it fails"
+    )]
+    before: Option<String>,
+
+    #[structopt(
+        long = "after",
+        help = "Run this shell command once after all the commands finished, with RARGS_COMMANDS and RARGS_FAILURES set"
+    )]
+    after: Option<String>,
+
+    #[structopt(
+        long = "stdin-passthrough",
+        conflicts_with = "stdin-inherit",
+        help = "Write the input line to the stdin of the command"
+    )]
+    stdin_passthrough: bool,
+
+    #[structopt(
+        long = "stdin-inherit",
+        help = "Let the command read from the stdin of rargs, e.g. the terminal"
+    )]
+    stdin_inherit: bool,
+
+    #[structopt(
+        long = "prefix",
+        help = "Capture the output of each command and prefix its lines with the line number"
+    )]
+    prefix: bool,
+
+    #[structopt(
+        long = "prefix-template",
+        help = "Capture the output of each command and prefix its lines with the template"
+    )]
+    prefix_template: Option<String>,
+
+    #[structopt(
+        long = "line-buffered",
+        alias = "flush",
+        help = "Flush the captured output of the commands after every line instead of after each command"
+    )]
+    line_buffered: bool,
+
+    #[structopt(
+        long = "print0-from-stdout",
+        conflicts_with = "dryrun",
+        help = "Re-emit the stdout of the commands with each line terminated by NUL(\\0) instead of a newline"
+    )]
+    print0_from_stdout: bool,
+
+    #[structopt(
+        long = "success-if-empty",
+        help = "Count the commands that print anything to stdout as failed, even if they exit with 0"
+    )]
+    success_if_empty: bool,
+
+    #[structopt(
+        long = "success-if-contains",
+        conflicts_with = "success-if-empty",
+        help = "Count the commands whose stdout has no line containing this string as failed"
+    )]
+    success_if_contains: Option<String>,
+
+    #[structopt(
+        long = "output-to",
+        conflicts_with_all = &[
+            "prefix",
+            "prefix-template",
+            "keep-order",
+            "print0-from-stdout",
+            "success-if-empty",
+            "success-if-contains",
+        ],
+        help = "Write the output of each command to the file at the expanded template path"
+    )]
+    output_to: Option<String>,
+
+    #[structopt(
+        long = "chunk-output",
+        conflicts_with_all = &["output-to", "keep-order"],
+        help = "Append the output of each command to the shard file at the expanded template path"
+    )]
+    chunk_output: Option<String>,
+
+    #[structopt(
+        long = "chdir",
+        alias = "working-dir",
+        help = "Run each command in the directory at the expanded template path"
+    )]
+    chdir: Option<String>,
+
+    #[structopt(
+        long = "replace-str",
+        short = "I",
+        help = "open and close delimiters of fields separated by a space, e.g. '%{ }%'"
+    )]
+    replace_str: Option<String>,
+
+    #[structopt(
+        long = "command-file",
+        conflicts_with = "cmd-and-args",
+        help = "Read the command and its arguments from the file, one per line"
+    )]
+    command_file: Option<String>,
+
+    #[structopt(
+        required_unless_one = &["command-file", "list-presets", "emit-json"],
+        help = "command to execute and its arguments"
+    )]
+    cmd_and_args: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Rargs {
+    script: Option<ArgTemplate>, // the command run with `sh -c` in --shell mode
+    delimiters: (String, String), // of the fields, for --check
+    patterns: Vec<Regex>,
+    fallback: Option<Regex>, // for lines matching none of multiple patterns
+    select: Option<Regex>,   // of --match
+    reject: Option<Regex>,   // of --no-match
+    splitter: Option<Regex>, // the delimiter alone, with --keep-empty-fields
+    keep_empty_fields: bool,
+    min_fields: usize,
+    ci_names: bool,
+    fields: Option<Regex>, // with --with-fields, splits the numbered groups
+    env_prefix: Option<String>,
+    single_match: bool,
+    trim: Option<Trim>,
+    append: Option<Append>,
+    json: bool,
+    csv_delimiter: Option<char>, // None if the input is not CSV
+    header: RwLock<HashMap<String, usize>>, // field name => index of the numbered group
+    line_count: OnceLock<usize>, // only set with --count-first
+    command: String,
+    args: Vec<ArgTemplate>,
+    default_sep: String, // for output range fields
+    output_sep: String,  // for the values left by filters in joined fields
+    prefix: Option<ArgTemplate>,
+    group_by: Option<ArgTemplate>,
+    dedupe_by: Option<ArgTemplate>, // the field of --dedupe-field
+    output_to: Option<ArgTemplate>,
+    chunk_output: Option<ArgTemplate>,
+    shards: Shards, // the open files of --chunk-output
+    chdir: Option<ArgTemplate>,
+    uses_prev: bool, // whether the previous line is passed along with each line
+    tty: Option<Mutex<BufReader<std::fs::File>>>, // answers to --prompt
+    null_output: bool, // for dry-run
+    quoted_output: bool,
+    verbose: bool,
+    tee: bool,
+    record_end: char, // after the printed commands, NUL with --output0
+    stats: Option<Stats>,
+    stats_json: bool,
+    color: bool,                          // for dry-run
+    stdout: Mutex<BufWriter<io::Stdout>>, // flushed after each command, or line with --line-buffered
+    stderr: Mutex<io::Stderr>,
+    line_buffered: bool,
+    halt_on_error: bool,
+    stdout_end: u8, // of the lines of the captured stdout, NUL with --print0-from-stdout
+    output_check: Option<OutputCheck>,
+    fail_fast: bool,
+    no_run_if_empty: bool,
+    timeout: Option<Duration>,
+    keep_order: bool, // capture the output of commands, to be printed in order
+    stdin_source: StdinSource,
+    rate_limit: Option<RateLimit>,
+    retries: usize,
+    retry_delay: Duration,
+    retry_on_codes: Vec<i32>, // empty to retry on any failure
+    commands: AtomicUsize,    // how many were run, for --after
+    failures: AtomicUsize,
+    exit_code: AtomicI32,
+    first_failure: OnceLock<i32>, // the exit code of the first failed child, for --fail-fast
+}
+
+/// What to strip from both ends of the captured groups
+#[derive(Debug)]
+enum Trim {
+    Whitespace,
+    Chars(String),
+}
+
+impl Trim {
+    fn apply<'a>(&self, value: &'a str) -> &'a str {
+        match self {
+            Trim::Whitespace => value.trim(),
+            Trim::Chars(chars) => value.trim_matches(|ch| chars.contains(ch)),
+        }
+    }
+}
+
+/// How the input line is added after the arguments with `--append-args`
+#[derive(Debug)]
+enum Append {
+    Line,
+    Words,
+}
+
+/// Where the commands read their stdin from
+#[derive(Debug)]
+enum StdinSource {
+    Null,
+    Passthrough, // the lines of the input the command is run for
+    Inherit,
+}
+
+/// What the stdout of a command must look like for it to succeed, besides exiting with 0
+#[derive(Debug)]
+enum OutputCheck {
+    Empty,
+    Contains(String), // on a single line
+}
+
+#[derive(Debug)]
+enum RargsError {
+    Pattern(regex::Error),
+    Options(String),
+    Template(String),
+    MissingField {
+        line_num: i32,
+        field: String,
+        line: String,
+    },
+    Io(String, io::Error),
+}
+
+impl fmt::Display for RargsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RargsError::Pattern(error) => write!(f, "invalid pattern: {}", error),
+            RargsError::Options(message) => write!(f, "{}", message),
+            RargsError::Template(message) => write!(f, "{}", message),
+            RargsError::MissingField {
+                line_num,
+                field,
+                line,
+            } => write!(
+                f,
+                "line {}: missing field {} in {:?}",
+                line_num, field, line
+            ),
+            RargsError::Io(path, error) => write!(f, "{}: {}", path, error),
+        }
+    }
+}
+
+impl From<regex::Error> for RargsError {
+    fn from(error: regex::Error) -> Self {
+        RargsError::Pattern(error)
+    }
+}
+
+impl Rargs {
+    pub fn new(opts: &Options) -> Result<Self, RargsError> {
+        if opts.record_separator.as_deref() == Some("") {
+            return Err(RargsError::Options(
+                "the record separator can't be empty".to_string(),
+            ));
+        }
+        if opts.delimiter_chars.as_deref() == Some("") {
+            return Err(RargsError::Options(
+                "--delimiter-chars needs at least one character".to_string(),
+            ));
+        }
+        if opts.dedupe_window.is_some() && !opts.dedupe && opts.dedupe_field.is_none() {
+            return Err(RargsError::Options(
+                "--dedupe-window requires --dedupe or --dedupe-field".to_string(),
+            ));
+        }
+        if opts.dedupe_window == Some(0) {
+            return Err(RargsError::Options(
+                "--dedupe-window must be at least 1".to_string(),
+            ));
+        }
+        if opts
+            .rate
+            .is_some_and(|rate| !(rate > 0.0 && rate.is_finite()))
+        {
+            return Err(RargsError::Options(
+                "--rate must be a positive number".to_string(),
+            ));
+        }
+        if opts.rate_burst.is_some() && opts.rate.is_none() {
+            return Err(RargsError::Options(
+                "--rate-burst requires --rate".to_string(),
+            ));
+        }
+        if opts.rate_burst == Some(0) {
+            return Err(RargsError::Options(
+                "--rate-burst must be at least 1".to_string(),
+            ));
+        }
+        if opts.dispatch_batch == 0 {
+            return Err(RargsError::Options(
+                "--dispatch-batch must be at least 1".to_string(),
+            ));
+        }
+        if opts.repeat == 0 {
+            return Err(RargsError::Options(
+                "--repeat must be at least 1".to_string(),
+            ));
+        }
+
+        let rate_limit = match opts.rate {
+            Some(rate) => Some(
+                RateLimit::new(rate, opts.rate_burst.unwrap_or(1)).map_err(RargsError::Options)?,
+            ),
+            None => None,
+        };
+
+        let has_pattern = !opts.pattern.is_empty() || !opts.preset.is_empty();
+        for (flag, set) in [
+            ("--single-match", opts.single_match),
+            ("--with-fields", opts.with_fields),
+        ] {
+            if set && !has_pattern {
+                return Err(RargsError::Options(format!(
+                    "{} requires --pattern or --preset",
+                    flag
+                )));
+            }
+        }
+
+        let whitespace = r"(.*?)[[:space:]]+|(.*?)$";
+        // runs of the characters of --delimiter-chars are a single delimiter, like in awk
+        let repeat = if opts.keep_empty_fields { "" } else { "+" };
+        let delimiter = if let Some(delimiter) = opts.delimiter.as_ref() {
+            Some(delimiter.to_string())
+        } else if let Some(delimiter) = opts.fixed_delimiter.as_ref() {
+            Some(regex::escape(delimiter))
+        } else {
+            opts.delimiter_chars.as_ref().map(|chars| {
+                let class = chars
+                    .chars()
+                    .map(|ch| regex::escape(&ch.to_string()))
+                    .collect::<String>();
+                format!("[{}]{}", class, repeat)
+            })
+        };
+        let compile = |pattern: &str| {
+            RegexBuilder::new(pattern)
+                .case_insensitive(opts.ignore_case)
+                .dot_matches_new_line(opts.dotall)
+                .multi_line(opts.multiline)
+                .build()
+        };
+        let patterns = if has_pattern {
+            let presets = opts
+                .preset
+                .iter()
+                .map(|name| preset::find(name))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(RargsError::Options)?;
+            opts.pattern
+                .iter()
+                .map(String::as_str)
+                .chain(presets)
+                .map(compile)
+                .collect::<Result<_, _>>()?
+        } else if let Some(delimiter) = delimiter.as_ref() {
+            let pat_string = format!(r"(.*?){}|(.*?)$", delimiter);
+            vec![compile(&pat_string)?]
+        } else {
+            vec![Regex::new(whitespace)?]
+        };
+        if opts.ci_names {
+            for pattern in patterns.iter() {
+                let mut names = HashMap::new();
+                for name in pattern.capture_names().flatten() {
+                    if let Some(other) = names.insert(name.to_lowercase(), name) {
+                        return Err(RargsError::Options(format!(
+                            "--ci-names: the groups {} and {} differ only by case",
+                            other, name
+                        )));
+                    }
+                }
+            }
+        }
+        // splitting on the delimiter keeps the empty fields the pattern would drop, like the
+        // one after a trailing delimiter
+        let splitter = match delimiter.as_ref().filter(|_| opts.keep_empty_fields) {
+            Some(delimiter) => Some(compile(delimiter)?),
+            None if opts.keep_empty_fields && !has_pattern => Some(Regex::new("[[:space:]]")?),
+            None => None,
+        };
+        let select = opts.select.as_deref().map(compile).transpose()?;
+        let reject = opts.reject.as_deref().map(compile).transpose()?;
+        let fallback = if opts.fallback_split {
+            Some(Regex::new(whitespace)?)
+        } else {
+            None
+        };
+        let fields = if opts.with_fields {
+            Some(Regex::new(whitespace)?)
+        } else {
+            None
+        };
+
+        let syntax = match opts.replace_str.as_ref() {
+            Some(replace_str) => {
+                FieldSyntax::from_replace_str(replace_str).map_err(RargsError::Options)?
+            }
+            None => FieldSyntax::default(),
+        }
+        .ignore_case(opts.ignore_case);
+        let parse_template =
+            |arg: &str| ArgTemplate::parse(arg, &syntax).map_err(RargsError::Template);
+        let delimiters = (syntax.open.clone(), syntax.close.clone());
+
+        let cmd_and_args = match opts.command_file.as_ref() {
+            Some(path) => read_command_file(path)?,
+            None => opts.cmd_and_args.clone(),
+        };
+
+        // the shell runs the command with the expanded args appended
+        let shell = match opts.shell_path.as_ref() {
+            Some(shell) => Some(shell.as_str()),
+            None if opts.shell => Some("sh"),
+            None => None,
+        };
+        let (command, script) = match (shell, cmd_and_args.first()) {
+            // only --emit-json runs without a command
+            (_, None) => (String::new(), None),
+            (Some(shell), Some(script)) => (
+                shell.to_string(),
+                Some(
+                    parse_template(script)?
+                        .quote(opts.quote)
+                        .strict(opts.strict),
+                ),
+            ),
+            (None, Some(command)) => (command.to_string(), None),
+        };
+        let args = cmd_and_args
+            .iter()
+            .skip(1)
+            .map(|s| {
+                parse_template(s).map(|t| t.quote(opts.quote).strict(opts.strict).each(opts.each))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let default_sep = unescape(&opts.separator);
+        let output_sep = unescape(&opts.output_separator);
+
+        let prefix = match opts.prefix_template.as_ref() {
+            Some(template) => Some(parse_template(template)?),
+            None if opts.prefix => Some(parse_template(&format!(
+                "[{}{}{}] ",
+                syntax.open, CONTEXT_KEY_LINENUM_SHORT, syntax.close
+            ))?),
+            None => None,
+        };
+        let group_by = match opts.group_by.as_ref() {
+            Some(template) => Some(parse_template(template)?),
+            None => None,
+        };
+        let dedupe_by = match opts.dedupe_field.as_ref() {
+            Some(field) => Some(parse_template(&format!(
+                "{}{}{}",
+                syntax.open, field, syntax.close
+            ))?),
+            None => None,
+        };
+        let output_to = match opts.output_to.as_ref() {
+            Some(template) => Some(parse_template(template)?),
+            None => None,
+        };
+        let chunk_output = match opts.chunk_output.as_ref() {
+            Some(template) => Some(parse_template(template)?),
+            None => None,
+        };
+        let chdir = match opts.chdir.as_ref() {
+            Some(template) => Some(parse_template(template)?),
+            None => None,
+        };
+
+        // the input is on stdin, so the answers are read from the terminal itself
+        let tty = if opts.prompt {
+            let file = std::fs::File::open("/dev/tty")
+                .map_err(|error| RargsError::Io("/dev/tty".to_string(), error))?;
+            Some(Mutex::new(BufReader::new(file)))
+        } else {
+            None
+        };
+
+        let uses_prev = script
+            .iter()
+            .chain(args.iter())
+            .chain(prefix.iter())
+            .chain(group_by.iter())
+            .chain(output_to.iter())
+            .chain(chunk_output.iter())
+            .chain(chdir.iter())
+            .any(ArgTemplate::uses_prev);
+
+        Ok(Rargs {
+            script,
+            delimiters,
+            patterns,
+            fallback,
+            select,
+            reject,
+            splitter,
+            keep_empty_fields: opts.keep_empty_fields,
+            min_fields: opts.min_fields.unwrap_or(0),
+            ci_names: opts.ci_names,
+            fields,
+            env_prefix: match opts.env_prefix.as_ref() {
+                Some(prefix) => Some(prefix.to_string()),
+                None if opts.env => Some(DEFAULT_ENV_PREFIX.to_string()),
+                None => None,
+            },
+            single_match: opts.single_match,
+            trim: match opts.trim_chars.as_ref() {
+                Some(chars) => Some(Trim::Chars(unescape(chars))),
+                None if opts.trim => Some(Trim::Whitespace),
+                None => None,
+            },
+            append: match (opts.append_args, opts.split_appended) {
+                (true, true) => Some(Append::Words),
+                (true, false) => Some(Append::Line),
+                (false, _) => None,
+            },
+            json: opts.json,
+            csv_delimiter: Some(opts.csv_delimiter).filter(|_| opts.csv),
+            header: RwLock::new(HashMap::new()),
+            line_count: OnceLock::new(),
+            command,
+            args,
+            default_sep,
+            output_sep,
+            prefix,
+            group_by,
+            dedupe_by,
+            output_to,
+            chunk_output,
+            shards: Shards::new(),
+            chdir,
+            uses_prev,
+            tty,
+            null_output: opts.null_output,
+            quoted_output: opts.quoted_output,
+            verbose: opts.verbose,
+            tee: opts.tee,
+            record_end: if opts.output0 { '\0' } else { '\n' },
+            stats: if opts.stats || opts.stats_json {
+                Some(Stats::new())
+            } else {
+                None
+            },
+            stats_json: opts.stats_json,
+            // NO_COLOR only turns off the default, see https://no-color.org
+            color: match opts.color.as_str() {
+                "always" => true,
+                "never" => false,
+                _ => {
+                    io::stdout().is_terminal()
+                        && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                }
+            },
+            stdout: Mutex::new(BufWriter::new(io::stdout())),
+            stderr: Mutex::new(io::stderr()),
+            line_buffered: opts.line_buffered,
+            stdout_end: if opts.print0_from_stdout {
+                b'\0'
+            } else {
+                b'\n'
+            },
+            output_check: if opts.success_if_empty {
+                Some(OutputCheck::Empty)
+            } else {
+                opts.success_if_contains.clone().map(OutputCheck::Contains)
+            },
+            halt_on_error: opts.halt_on_error || opts.fail_fast,
+            fail_fast: opts.fail_fast,
+            no_run_if_empty: opts.no_run_if_empty,
+            timeout: opts.timeout.map(Duration::from_secs),
+            keep_order: opts.keep_order,
+            stdin_source: if opts.stdin_passthrough {
+                StdinSource::Passthrough
+            } else if opts.stdin_inherit {
+                StdinSource::Inherit
+            } else {
+                StdinSource::Null
+            },
+            rate_limit,
+            retries: opts.retries,
+            retry_delay: Duration::from_secs(opts.retry_delay),
+            retry_on_codes: opts.retry_on_codes.clone(),
+            commands: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0),
+            first_failure: OnceLock::new(),
+            exit_code: AtomicI32::new(0),
+        })
+    }
+
+    fn build_context<'a>(&'a self, input: &Line<'a>) -> InputContext<'a> {
+        let Line {
+            text: line,
+            num: line_num,
+            prev,
+            repeat,
+        } = *input;
+        // invalid JSON lines are skipped while reading the input
+        let value = if self.json {
+            Some(serde_json::from_str(line).unwrap_or(Value::Null))
+        } else {
+            None
+        };
+
+        let mut fields = self
+            .split_fields(line, value.as_ref())
+            .pad_groups(self.min_fields)
+            .fold_names(self.ci_names)
+            .trim_groups(self.trim.as_ref())
+            .name_groups(&self.header.read().unwrap())
+            .default_sep(Cow::Borrowed(&self.default_sep))
+            .output_sep(Cow::Borrowed(&self.output_sep))
+            .put(CONTEXT_KEY_LINENUM, Cow::Owned(line_num.to_string()))
+            .put(CONTEXT_KEY_LINENUM_SHORT, Cow::Owned(line_num.to_string()))
+            .put(CONTEXT_KEY_LINE, Cow::Borrowed(line))
+            .put(CONTEXT_KEY_LINE_SHORT, Cow::Borrowed(line))
+            .put(CONTEXT_KEY_REPEAT, Cow::Owned(repeat.to_string()));
+        if let Some(prev) = prev {
+            let prev = Line {
+                text: prev,
+                prev: None,
+                ..*input
+            };
+            for (name, value) in self.build_context(&prev).variables() {
+                if !SPECIAL_KEYS.contains(&name.as_str()) {
+                    fields = fields.put(&format!("{}{}", PREV_PREFIX, name), Cow::Owned(value));
+                }
+            }
+        }
+        if let Some(line_count) = self.line_count.get() {
+            fields = fields
+                .put(CONTEXT_KEY_LINECOUNT, Cow::Owned(line_count.to_string()))
+                .put(
+                    CONTEXT_KEY_LINECOUNT_SHORT,
+                    Cow::Owned(line_count.to_string()),
+                );
+        }
+        let fields = fields.build();
+
+        match value {
+            Some(value) => InputContext::Json(JsonContext { value, fields }),
+            None => InputContext::Regex(fields),
+        }
+    }
+
+    fn split_fields<'a>(&'a self, line: &'a str, value: Option<&Value>) -> RegexContext<'a> {
+        match (value, self.csv_delimiter) {
+            (Some(value), _) => RegexContext::from_groups(line, json_groups(value)),
+            (None, Some(delimiter)) => RegexContext::from_groups(
+                line,
+                csv::split(line, delimiter)
+                    .into_iter()
+                    .map(Cow::Owned)
+                    .collect(),
+            ),
+            // lines without a pattern are skipped while reading the input
+            (None, None) => {
+                if let Some(splitter) = self.splitter.as_ref() {
+                    return RegexContext::from_split(splitter, line);
+                }
+                let context = RegexContext::builder(
+                    self.select_pattern(line).unwrap_or(&self.patterns[0]),
+                    line,
+                    self.single_match,
+                    self.keep_empty_fields,
+                );
+                match self.fields.as_ref() {
+                    Some(fields) => {
+                        RegexContext::builder(fields, line, false, false).names_from(context)
+                    }
+                    None => context,
+                }
+            }
+        }
+    }
+
+    /// Whether any of the patterns captures anything, the fields of JSON and CSV always match
+    fn is_matched(&self, line: &str) -> bool {
+        self.json
+            || self.csv_delimiter.is_some()
+            || self.patterns.iter().any(|pattern| pattern.is_match(line))
+    }
+
+    /// Whether the line passes `--match` and `--no-match`
+    fn is_selected(&self, line: &str) -> bool {
+        self.select
+            .as_ref()
+            .is_none_or(|select| select.is_match(line))
+            && !self
+                .reject
+                .as_ref()
+                .is_some_and(|reject| reject.is_match(line))
+    }
+
+    /// A single pattern is used even if it doesn't match. Of multiple patterns the first matching
+    /// one is used, or the fallback if none matches.
+    fn select_pattern(&self, line: &str) -> Option<&Regex> {
+        match &self.patterns[..] {
+            [pattern] => Some(pattern),
+            patterns => patterns
+                .iter()
+                .find(|pattern| pattern.is_match(line))
+                .or(self.fallback.as_ref()),
+        }
+    }
+
+    /// Name the numbered fields of the following lines after the fields of the header line
+    fn set_header(&self, line: &str) {
+        let header = self
+            .split_fields(line, None)
+            .groups
+            .iter()
+            .enumerate()
+            .map(|(idx, name)| {
+                let name = name.trim();
+                // like with duplicate names, the last of the fields differing by case wins
+                let name = if self.ci_names {
+                    name.to_lowercase()
+                } else {
+                    name.to_string()
+                };
+                (name, idx + 1)
+            })
+            .collect();
+        *self.header.write().unwrap() = header;
+    }
+
+    fn get_args(&self, line: &Line) -> Result<Vec<String>, RargsError> {
+        let context = self.build_context(line);
+
+        let mut args = vec![];
+        for arg in self.args.iter() {
+            args.extend(self.expand(arg, &context, line)?);
+        }
+        match self.append {
+            Some(Append::Line) => args.push(line.text.to_string()),
+            Some(Append::Words) => args.extend(line.text.split_whitespace().map(str::to_string)),
+            None => {}
+        }
+        Ok(args)
+    }
+
+    fn expand<'a>(
+        &self,
+        template: &'a ArgTemplate,
+        context: &'a InputContext<'a>,
+        line: &Line,
+    ) -> Result<Vec<String>, RargsError> {
+        template
+            .apply_context(context)
+            .map_err(|field| RargsError::MissingField {
+                line_num: line.num,
+                field,
+                line: line.text.to_string(),
+            })
+    }
+
+    /// The args of the command for the batch. In --shell mode they are appended to the command
+    /// expanded against the first line of the batch, and passed to the shell as a single script.
+    fn get_command_args(&self, batch: &[Line]) -> Result<Vec<String>, RargsError> {
+        let args = self.get_batch_args(batch)?;
+        let script = match self.script.as_ref() {
+            Some(script) => script,
+            None => return Ok(args),
+        };
+
+        let context = self.build_context(&batch[0]);
+        let mut words = self.expand(script, &context, &batch[0])?;
+        words.extend(args);
+        Ok(vec!["-c".to_string(), words.join(" ")])
+    }
+
+    /// With `--no-run-if-empty`, a batch is skipped if no line expands to anything but blanks
+    fn should_skip(&self, batch: &[Line]) -> bool {
+        self.no_run_if_empty
+            && batch.iter().all(|line| {
+                let context = self.build_context(line);
+                self.args.iter().all(|arg| arg.is_blank_in(&context))
+            })
+    }
+
+    /// Expand the templates for every line of the batch, appending the args in order
+    fn get_batch_args(&self, batch: &[Line]) -> Result<Vec<String>, RargsError> {
+        let mut args = vec![];
+        for line in batch {
+            args.extend(self.get_args(line)?);
+        }
+        Ok(args)
+    }
+
+    /// The prefix for captured output is expanded against the first line of the batch
+    fn get_prefix(&self, template: &ArgTemplate, batch: &[Line]) -> String {
+        self.expand_joined(template, &batch[0])
+    }
+
+    /// The key of the line with `--group-by`, lines are grouped while it stays the same
+    fn group_key(&self, line: &Line) -> Option<String> {
+        let template = self.group_by.as_ref()?;
+        Some(self.expand_joined(template, line))
+    }
+
+    /// The field of `--dedupe-field`, or the whole line with `--dedupe`
+    fn dedupe_key(&self, line: &Line) -> String {
+        match self.dedupe_by.as_ref() {
+            Some(template) => self.expand_joined(template, line),
+            None => line.text.to_string(),
+        }
+    }
+
+    fn expand_joined(&self, template: &ArgTemplate, line: &Line) -> String {
+        let context = self.build_context(line);
+        template
+            .apply_context(&context)
+            .unwrap_or_default()
+            .join(" ")
+    }
+
+    /// Returns the output of the command if it is captured with `--keep-order`
+    fn execute_for_input(&self, batch: &[Line]) -> Output {
+        if self.should_skip(batch) {
+            return Output::default();
+        }
 
-    let stdin = io::stdin();
+        let args = match self.get_command_args(batch) {
+            Ok(args) => args,
+            Err(error) => {
+                eprintln!("rargs: {}", error);
+                self.record_failure(EXIT_INVALID_INPUT);
+                return Output::default();
+            }
+        };
 
-    let num_worker = if options.worker > 0 {
-        options.worker
-    } else {
-        num_cpus::get()
-    };
-    let num_threads = if options.threads > 0 {
-        options.threads
-    } else {
-        num_worker
-    };
+        let mut command = Command::new(&self.command);
+        command.args(&args);
+        if !self.confirm(&command) {
+            return Output::default();
+        }
 
-    let pool = ThreadPool::new(num_threads);
+        if let Some(env_prefix) = self.env_prefix.as_ref() {
+            for (name, value) in self.build_context(&batch[0]).variables() {
+                command.env(format!("{}{}", env_prefix, name), value);
+            }
+        }
 
-    let line_ending = if options.read0 { b'\0' } else { b'\n' };
-    let mut line_num = options.startnum - 1;
-    loop {
-        let mut buffer = Vec::with_capacity(1024);
-        match stdin.lock().read_until(line_ending, &mut buffer) {
-            Ok(n) => {
-                if n == 0 {
-                    break;
+        let input = match self.stdin_source {
+            StdinSource::Null => {
+                command.stdin(Stdio::null());
+                None
+            }
+            StdinSource::Passthrough => {
+                command.stdin(Stdio::piped());
+                Some(batch.iter().fold(Vec::new(), |mut input, line| {
+                    input.extend_from_slice(line.text.as_bytes());
+                    input.push(b'\n');
+                    input
+                }))
+            }
+            StdinSource::Inherit => {
+                command.stdin(Stdio::inherit());
+                None
+            }
+        };
+        let input = input.as_deref();
+
+        if let Some(template) = self.chdir.as_ref() {
+            let dir = self.expand_joined(template, &batch[0]);
+            if !Path::new(&dir).is_dir() {
+                eprintln!("rargs: {}: no such directory", dir);
+                self.record_failure(EXIT_CANNOT_RUN);
+                return Output::default();
+            }
+            command.current_dir(dir);
+        }
+
+        if let Some(template) = self.output_to.as_ref() {
+            match create_output(&self.expand_joined(template, &batch[0])) {
+                Ok(file) => {
+                    command.stdout(file);
                 }
+                Err(error) => {
+                    eprintln!("rargs: {}", error);
+                    self.record_failure(EXIT_CANNOT_RUN);
+                    return Output::default();
+                }
+            }
+        }
 
-                // remove line-ending
-                if buffer.ends_with(&[b'\r', b'\n']) {
-                    buffer.pop();
-                    buffer.pop();
-                } else if buffer.ends_with(&[b'\n']) || buffer.ends_with(&[b'\0']) {
-                    buffer.pop();
+        let shard = match self.chunk_output.as_ref() {
+            Some(template) => match self.shards.open(&self.expand_joined(template, &batch[0])) {
+                Ok(shard) => Some(shard),
+                Err(error) => {
+                    eprintln!("rargs: {}", error);
+                    self.record_failure(EXIT_CANNOT_RUN);
+                    return Output::default();
                 }
+            },
+            None => None,
+        };
 
-                // execute command on line
-                let rargs = rargs.clone();
-                line_num += 1;
-                let line = String::from_utf8(buffer).expect("Found invalid UTF8");
+        let prefix = self
+            .prefix
+            .as_ref()
+            .map(|template| self.get_prefix(template, batch));
 
-                if options.dryrun {
-                    rargs.print_commands_to_be_executed(&line, line_num);
-                } else {
-                    pool.execute(move || {
-                        rargs.execute_for_input(&line, line_num);
-                    });
+        // with --chunk-output the whole stdout is appended at once, not to mix with other commands
+        let captured = if self.keep_order || shard.is_some() {
+            Some((Mutex::new(Vec::new()), Mutex::new(Vec::new())))
+        } else {
+            None
+        };
+
+        if self.verbose {
+            let mut stderr = self.stderr.lock().unwrap();
+            let _ = write!(
+                stderr,
+                "+ {} # {}{}",
+                self.quote_command(&args),
+                describe_lines(batch),
+                self.record_end
+            );
+        }
+        // ahead of the output of the command, in its captured output with --keep-order
+        if self.tee {
+            let printed = format!(
+                "{} # {}{}",
+                self.quote_command(&args),
+                describe_lines(batch),
+                self.record_end
+            );
+            match captured.as_ref() {
+                Some((stdout, _)) => stdout.lock().unwrap().extend_from_slice(printed.as_bytes()),
+                None => {
+                    let mut stdout = self.stdout.lock().unwrap();
+                    let _ = stdout
+                        .write_all(printed.as_bytes())
+                        .and_then(|_| stdout.flush());
+                }
+            }
+        }
+
+        self.commands.fetch_add(1, Ordering::SeqCst);
+        // only the attempts are timed, not the waits for `--rate` or the delays between retries
+        let mut elapsed = Duration::ZERO;
+        let mut delay = self.retry_delay;
+        let mut attempt = 0;
+        let status = loop {
+            // every attempt counts, a retry is a request to the API as well
+            if let Some(rate_limit) = self.rate_limit.as_ref() {
+                rate_limit.acquire();
+            }
+            let start = Instant::now();
+            let status = match (prefix.as_ref(), captured.as_ref()) {
+                (None, None) if self.stdout_end == b'\n' && self.output_check.is_none() => {
+                    spawn_child(&mut command, input)
+                        .and_then(|mut child| self.wait_child(&mut child))
+                }
+                // the output is checked, or its line endings are rewritten, as it is forwarded
+                (prefix, None) => {
+                    let prefix = prefix.map_or("", |prefix| prefix.as_str());
+                    self.run_with_prefix(&mut command, input, prefix, &self.stdout, &self.stderr)
+                }
+                (prefix, Some((stdout, stderr))) => {
+                    let prefix = prefix.map_or("", |prefix| prefix.as_str());
+                    self.run_with_prefix(&mut command, input, prefix, stdout, stderr)
                 }
+            };
+            elapsed += start.elapsed();
+
+            if attempt >= self.retries || !self.should_retry(&status) || self.should_halt() {
+                break status;
+            }
+            attempt += 1;
+            eprintln!(
+                "rargs: retry {}/{}: {}",
+                attempt,
+                self.retries,
+                describe_command(&command)
+            );
+            thread::sleep(delay);
+            delay *= 2;
+        };
+        if let Some(stats) = self.stats.as_ref() {
+            stats.record(elapsed);
+        }
+
+        match status {
+            Ok(Some(status)) if status.success() => {}
+            // no exit code means the child was terminated by a signal
+            Ok(Some(status)) => {
+                // like shells do, 128 plus the number of the signal that terminated the child
+                let code = status
+                    .code()
+                    .unwrap_or_else(|| 128 + status.signal().unwrap_or(0));
+                let _ = self.first_failure.set(code);
+                self.record_failure(
+                    status
+                        .code()
+                        .map_or(EXIT_CHILD_SIGNALED, |_| EXIT_CHILD_FAILED),
+                );
+            }
+            Ok(None) => {
+                eprintln!("rargs: timeout: {}", describe_command(&command));
+                self.record_failure(EXIT_CHILD_SIGNALED);
             }
-            Err(_err) => {
-                // String not UTF8 or other error, skip.
-                exit_code = 1;
-                break;
+            Err(error) => {
+                eprintln!("rargs: {}: {}", self.command, error);
+                self.record_failure(EXIT_CANNOT_RUN);
             }
         }
+
+        match (captured, shard) {
+            (Some((stdout, stderr)), Some(shard)) => {
+                Shards::append(&shard, &stdout.into_inner().unwrap());
+                let _ = self
+                    .stderr
+                    .lock()
+                    .unwrap()
+                    .write_all(&stderr.into_inner().unwrap());
+                Output::default()
+            }
+            (Some((stdout, stderr)), None) => Output {
+                stdout: stdout.into_inner().unwrap(),
+                stderr: stderr.into_inner().unwrap(),
+            },
+            (None, _) => Output::default(),
+        }
     }
 
-    pool.join();
-    std::process::exit(exit_code);
-}
+    /// With `--prompt`, ask whether to run the command. Only an answer starting with "y" runs it.
+    fn confirm(&self, command: &Command) -> bool {
+        let tty = match self.tty.as_ref() {
+            Some(tty) => tty,
+            None => return true,
+        };
 
-lazy_static! {
-    static ref CMD_REGEX: Regex = Regex::new(r"\{[[:space:]]*[^{}]*[[:space:]]*\}").unwrap();
-    static ref FIELD_NAMED: Regex =
-        Regex::new(r"^\{[[:space:]]*(?P<name>[[:word:]]*)[[:space:]]*\}$").unwrap();
-    static ref FIELD_SINGLE: Regex =
-        Regex::new(r"^\{[[:space:]]*(?P<num>-?\d+)[[:space:]]*\}$").unwrap();
-    static ref FIELD_RANGE: Regex =
-        Regex::new(r"^\{(?P<left>-?\d*)?\.\.(?P<right>-?\d*)?(?::(?P<sep>.*))?\}$").unwrap();
-    static ref FIELD_SPLIT_RANGE: Regex =
-        Regex::new(r"^\{(?P<left>-?\d*)?\.\.\.(?P<right>-?\d*)?\}$").unwrap();
-}
+        eprint!("{} ?...", describe_command(command));
+        let mut answer = String::new();
+        match tty.lock().unwrap().read_line(&mut answer) {
+            Ok(_) => answer.trim_start().starts_with(['y', 'Y']),
+            Err(_) => false,
+        }
+    }
 
-#[derive(StructOpt, Debug)]
-#[structopt(name = "Rargs", about = "Xargs with pattern matching")]
-#[structopt(settings = &[AppSettings::TrailingVarArg])]
-struct Options {
-    #[structopt(
-        long = "read0",
-        short = "0",
-        help = "Read input delimited by ASCII NUL(\\0) characters"
-    )]
-    read0: bool,
+    /// The exit code of rargs is the most severe one among all failures
+    fn record_failure(&self, exit_code: i32) {
+        let _ = self.first_failure.set(exit_code);
+        self.failures.fetch_add(1, Ordering::SeqCst);
+        self.exit_code.fetch_max(exit_code, Ordering::SeqCst);
+    }
 
-    #[structopt(
-        long = "worker",
-        short = "w",
-        default_value = "1",
-        help = "Deprecated. Number of threads to be used (same as --threads)"
-    )]
-    worker: usize,
+    /// Commands that failed to run are never retried, neither are timed out ones if
+    /// `--retry-on-codes` is given
+    fn should_retry(&self, status: &io::Result<Option<ExitStatus>>) -> bool {
+        match status {
+            Ok(Some(status)) if status.success() => false,
+            Ok(Some(status)) if !self.retry_on_codes.is_empty() => status
+                .code()
+                .is_some_and(|code| self.retry_on_codes.contains(&code)),
+            Ok(_) => !self.should_halt(),
+            Err(_) => false,
+        }
+    }
 
-    #[structopt(
-        long = "threads",
-        short = "j",
-        default_value = "1",
-        help = "Number of threads to be used"
-    )]
-    threads: usize,
+    fn should_halt(&self) -> bool {
+        INTERRUPTED.load(Ordering::SeqCst)
+            || (self.halt_on_error && self.failures.load(Ordering::SeqCst) > 0)
+    }
 
-    #[structopt(
-        long = "pattern",
-        short = "p",
-        help = "regex pattern that captures the input"
-    )]
-    pattern: Option<String>,
+    /// Wait for the child to exit. Returns `None` if it was killed because of `--timeout`. With
+    /// `--fail-fast` it's stopped once another command failed, and its status is returned.
+    fn wait_child(&self, child: &mut Child) -> io::Result<Option<ExitStatus>> {
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        if deadline.is_none() && !self.fail_fast {
+            return child.wait().map(Some);
+        }
 
-    #[structopt(
-        long = "separator",
-        short = "s",
-        default_value = " ",
-        help = "separator for ranged fields"
-    )]
-    separator: String,
+        loop {
+            let until = match deadline {
+                Some(deadline) if !self.fail_fast => deadline,
+                _ => Instant::now() + WAIT_POLL_INTERVAL,
+            };
+            if let Some(status) = wait_until(child, deadline.map_or(until, |d| min(d, until)))? {
+                return Ok(Some(status));
+            }
 
-    #[structopt(
-        long = "startnum",
-        short = "n",
-        default_value = "1",
-        help = "start value for line number"
-    )]
-    startnum: i32,
+            if self.fail_fast && self.failures.load(Ordering::SeqCst) > 0 {
+                return stop(child).map(Some);
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                stop(child)?;
+                return Ok(None);
+            }
+        }
+    }
 
-    #[structopt(
-        long = "delimiter",
-        short = "d",
-        conflicts_with = "pattern",
-        help = "regex pattern used as delimiter (conflict with pattern)"
-    )]
-    delimiter: Option<String>,
+    fn run_with_prefix<O: Write + Send, E: Write + Send>(
+        &self,
+        command: &mut Command,
+        input: Option<&[u8]>,
+        prefix: &str,
+        stdout: &Mutex<O>,
+        stderr: &Mutex<E>,
+    ) -> io::Result<Option<ExitStatus>> {
+        let mut child = spawn_child(command.stdout(Stdio::piped()).stderr(Stdio::piped()), input)?;
+
+        let child_stdout = child.stdout.take().expect("child stdout is piped");
+        let child_stderr = child.stderr.take().expect("child stderr is piped");
+
+        let flush = self.line_buffered;
+        let check = self.output_check.as_ref();
+        let (status, passed) = thread::scope(|scope| {
+            let forwarded = scope.spawn(|| {
+                forward_with_prefix(child_stdout, prefix, self.stdout_end, stdout, flush, check)
+            });
+            scope.spawn(|| forward_with_prefix(child_stderr, prefix, b'\n', stderr, flush, None));
+            let status = self.wait_child(&mut child);
+            (status, forwarded.join().unwrap_or(true))
+        });
+        let _ = stdout.lock().unwrap().flush();
+        match status {
+            // as if it exited with 1, so that it is retried and counted like any other failure
+            Ok(Some(status)) if status.success() && !passed => {
+                match check {
+                    Some(OutputCheck::Contains(needle)) => eprintln!(
+                        "rargs: output without {:?}: {}",
+                        needle,
+                        describe_command(command)
+                    ),
+                    _ => eprintln!("rargs: unexpected output: {}", describe_command(command)),
+                }
+                Ok(Some(ExitStatus::from_raw(1 << 8)))
+            }
+            status => status,
+        }
+    }
 
-    #[structopt(
-        long = "dry-run",
-        short = "e",
-        help = "Print the commands to be executed without actually execute"
-    )]
-    dryrun: bool,
+    /// With `--check`, describe the fields of the script and the arguments. Returns false if a
+    /// field is malformed, it would be passed on literally otherwise.
+    fn check(&self) -> bool {
+        let mut valid = true;
+        let templates = self
+            .script
+            .iter()
+            .map(|script| ("script".to_string(), script))
+            .chain(
+                self.args
+                    .iter()
+                    .enumerate()
+                    .map(|(i, arg)| (format!("arg {}", i + 1), arg)),
+            );
+        for (label, template) in templates {
+            println!("{}: {}", label, template.source);
+            for fragment in template.fragments.iter() {
+                if let Some(description) = fragment.describe() {
+                    println!(
+                        "  {}: {}",
+                        fragment.to_template(&self.delimiters.0, &self.delimiters.1),
+                        description
+                    );
+                }
+                for field in fragment.malformed(&self.delimiters.0, &self.delimiters.1) {
+                    eprintln!("rargs: {}: malformed field: {}", label, field);
+                    valid = false;
+                }
+            }
+        }
+        valid
+    }
 
-    #[structopt(required = true, help = "command to execute and its arguments")]
-    cmd_and_args: Vec<String>,
-}
+    /// With `--emit-json`, print the fields of every line as a JSON object on its own line
+    fn emit_json(&self, batch: &[Line]) {
+        let mut stdout = self.stdout.lock().unwrap();
+        for line in batch {
+            let object = match self.build_context(line) {
+                InputContext::Regex(context) => context.to_json(line.num),
+                InputContext::Json(_) => unreachable!("--emit-json conflicts with --json"),
+            };
+            let _ = writeln!(stdout, "{}", object);
+        }
+    }
 
-#[derive(Debug)]
-struct Rargs {
-    pattern: Regex,
-    command: String,
-    args: Vec<ArgTemplate>,
-    default_sep: String, // for output range fields
-}
+    fn print_commands_to_be_executed(&self, batch: &[Line]) {
+        if self.should_skip(batch) {
+            return;
+        }
 
-impl Rargs {
-    pub fn new(opts: &Options) -> Self {
-        let pattern;
+        let args = match self.get_command_args(batch) {
+            Ok(args) => args,
+            Err(error) => {
+                eprintln!("rargs: {}", error);
+                self.record_failure(EXIT_INVALID_INPUT);
+                return;
+            }
+        };
 
-        if let Some(pat_string) = opts.pattern.as_ref() {
-            pattern = Regex::new(pat_string).unwrap();
-        } else if let Some(delimiter) = opts.delimiter.as_ref() {
-            let pat_string = format!(r"(.*?){}|(.*?)$", delimiter);
-            pattern = Regex::new(&pat_string).unwrap();
+        if self.null_output {
+            let mut record = Vec::new();
+            for word in std::iter::once(&self.command).chain(args.iter()) {
+                record.extend_from_slice(word.as_bytes());
+                record.push(b'\0');
+            }
+            record.push(b'\0');
+
+            let _ = self.stdout.lock().unwrap().write_all(&record);
+        } else if self.quoted_output {
+            print!(
+                "{}{} # {}{}",
+                self.highlight(batch),
+                self.quote_command(&args),
+                describe_lines(batch),
+                self.record_end
+            );
         } else {
-            pattern = Regex::new(r"(.*?)[[:space:]]+|(.*?)$").unwrap();
+            print!(
+                "{}{} {}{}",
+                self.highlight(batch),
+                self.command,
+                args.join(" "),
+                self.record_end
+            );
         }
+    }
 
-        let command = opts.cmd_and_args[0].to_string();
-        let args = opts.cmd_and_args[1..]
-            .iter()
-            .map(|s| ArgTemplate::from(&**s))
-            .collect();
-        let default_sep = opts.separator.clone();
+    /// The command and its arguments shell-quoted, so that it can be copied into a shell
+    fn quote_command(&self, args: &[String]) -> String {
+        std::iter::once(&self.command)
+            .chain(args)
+            .map(|word| shell_quote(word))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
 
-        Rargs {
-            pattern,
-            command,
-            args,
-            default_sep,
+    /// The lines of the batch with their groups colored as comments, with `--color` only. The
+    /// fields of `--csv` and `--json` are not matched by a pattern, so there is nothing to color.
+    fn highlight(&self, batch: &[Line]) -> String {
+        // the comments would be records of their own with --output0
+        if !self.color || self.json || self.csv_delimiter.is_some() || self.record_end != '\n' {
+            return String::new();
+        }
+
+        let max_matches = if self.single_match { 1 } else { usize::MAX };
+        let mut highlighted = String::new();
+        for line in batch {
+            if let Some(pattern) = self.select_pattern(line.text) {
+                highlighted.push_str("# ");
+                highlighted.push_str(&color::highlight(line.text, pattern, max_matches));
+                highlighted.push('\n');
+            }
         }
+        highlighted
     }
+}
 
-    fn get_args(&self, line: &str, line_num: i32) -> Vec<String> {
-        let context = RegexContext::builder(&self.pattern, line)
-            .default_sep(Cow::Borrowed(&self.default_sep))
-            .put(CONTEXT_KEY_LINENUM, Cow::Owned(line_num.to_string()))
-            .put(CONTEXT_KEY_LINENUM_SHORT, Cow::Owned(line_num.to_string()))
-            .build();
+/// Read from the file at `path`, or from stdin if there is none
+fn open_input(path: Option<&str>) -> Result<Box<dyn BufRead>, RargsError> {
+    match path {
+        Some(path) => match std::fs::File::open(path) {
+            Ok(file) => Ok(Box::new(BufReader::new(file))),
+            Err(error) => Err(RargsError::Io(path.to_string(), error)),
+        },
+        None => Ok(Box::new(io::stdin().lock())),
+    }
+}
 
-        self.args
-            .iter()
-            .flat_map(|arg| arg.apply_context(&context))
-            .collect()
+/// Create or truncate the output file of a command, along with its missing parent directories
+fn create_output(path: &str) -> Result<std::fs::File, RargsError> {
+    let io_error = |error| RargsError::Io(path.to_string(), error);
+    match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => {
+            std::fs::create_dir_all(parent).map_err(io_error)?
+        }
+        _ => {}
+    }
+    std::fs::File::create(path).map_err(io_error)
+}
+
+/// Read the command and its arguments, one per line. Blank lines and `#` comments are ignored.
+fn read_command_file(path: &str) -> Result<Vec<String>, RargsError> {
+    let content = std::fs::read_to_string(path).map_err(|e| RargsError::Io(path.to_string(), e))?;
+    let cmd_and_args = content
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|line| line.to_string())
+        .collect::<Vec<_>>();
+
+    if cmd_and_args.is_empty() {
+        return Err(RargsError::Options(format!("{}: no command found", path)));
+    }
+    Ok(cmd_and_args)
+}
+
+/// Spawn the command and write the input to its piped stdin, if there is any
+fn spawn_child(command: &mut Command, input: Option<&[u8]>) -> io::Result<Child> {
+    let mut child = command.spawn()?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        // written from another thread so that a command not reading its stdin can't block us,
+        // stdin is closed once all the input is written
+        let input = input.to_vec();
+        thread::spawn(move || {
+            let _ = stdin.write_all(&input);
+        });
+    }
+    Ok(child)
+}
+
+/// Poll the child until it exits or the deadline passes
+fn wait_until(child: &mut Child, deadline: Instant) -> io::Result<Option<ExitStatus>> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        thread::sleep(WAIT_POLL_INTERVAL.min(deadline - now));
+    }
+}
+
+extern "C" fn on_interrupt(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// On the first SIGINT, stop starting commands and wait for the running ones, which get the
+/// signal from the terminal as well. The handler is reset, so a second SIGINT kills rargs.
+fn handle_interrupt() {
+    let handler: extern "C" fn(libc::c_int) = on_interrupt;
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESETHAND | libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+    }
+}
+
+/// Terminate the child, and kill it if it's still running after a grace period
+fn stop(child: &mut Child) -> io::Result<ExitStatus> {
+    terminate(child);
+    match wait_until(child, Instant::now() + TIMEOUT_KILL_GRACE)? {
+        Some(status) => Ok(status),
+        None => {
+            child.kill()?;
+            child.wait()
+        }
     }
+}
 
-    fn execute_for_input(&self, line: &str, line_num: i32) {
-        let args = self.get_args(line, line_num);
+/// Ask the child to exit with SIGTERM
+fn terminate(child: &Child) {
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+}
 
-        let status = Command::new(&self.command)
-            .args(args)
-            .stdin(Stdio::null())
-            .status();
+fn describe_command(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|s| s.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
 
-        if let Err(error) = status {
-            eprintln!("rargs: {}: {}", self.command, error);
+/// The numbers of the input lines of a command, e.g. "line 3" or "lines 3-5"
+fn describe_lines(batch: &[Line]) -> String {
+    match (batch.first(), batch.last()) {
+        (Some(first), Some(last)) if first.num != last.num => {
+            format!("lines {}-{}", first.num, last.num)
         }
+        (Some(first), _) => format!("line {}", first.num),
+        _ => String::new(),
     }
+}
 
-    fn print_commands_to_be_executed(&self, line: &str, line_num: i32) {
-        let args = self.get_args(line, line_num);
-        println!("{} {}", self.command, args.join(" "));
+/// Copy the output of a child line by line, prefixing each line and terminating it with `end`.
+/// Every line is written while holding the lock of the shared writer so that the output of
+/// concurrent children won't tear.
+///
+/// Returns whether the output passes the check, which it always does without one.
+fn forward_with_prefix<R: Read, W: Write>(
+    reader: R,
+    prefix: &str,
+    end: u8,
+    writer: &Mutex<W>,
+    flush: bool,
+    check: Option<&OutputCheck>,
+) -> bool {
+    let mut passed = !matches!(check, Some(OutputCheck::Contains(_)));
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                match check {
+                    Some(OutputCheck::Empty) => passed = false,
+                    Some(OutputCheck::Contains(needle)) if !passed => {
+                        passed = String::from_utf8_lossy(&line).contains(needle.as_str());
+                    }
+                    _ => {}
+                }
+                // the last line is terminated too
+                if line.ends_with(b"\n") {
+                    line.pop();
+                }
+                line.push(end);
+
+                let mut writer = writer.lock().unwrap();
+                let _ = writer
+                    .write_all(prefix.as_bytes())
+                    .and_then(|_| writer.write_all(&line));
+                if flush {
+                    let _ = writer.flush();
+                }
+            }
+        }
     }
+    passed
 }
 
 trait Context<'a> {
     fn get_by_name(&'a self, group_name: &str) -> Option<Cow<'a, str>>;
     fn get_by_range(&'a self, range: &Range, sep: Option<&str>) -> Option<Cow<'a, str>>;
     fn get_by_split_range(&'a self, range: &Range) -> Vec<Cow<'a, str>>;
+    /// Joins the values that filters like `on` leave in a joined field
+    fn output_separator(&'a self) -> &'a str;
 }
 
 /// The context parsed from the input line using the pattern given. For Example:
@@ -247,11 +2787,25 @@ trait Context<'a> {
 struct RegexContext<'a> {
     map: HashMap<String, Cow<'a, str>>,
     groups: Vec<Cow<'a, str>>,
+    offsets: HashMap<String, usize>, // byte offsets of the captured groups, by name and number
     default_sep: Cow<'a, str>,
+    output_sep: Cow<'a, str>,
+    ci_names: bool, // the names are in lowercase, the special variables excepted
+    matches: Vec<regex::Captures<'a>>, // of the pattern, one by one
 }
 
 impl<'a> RegexContext<'a> {
-    fn builder(pattern: &'a Regex, content: &'a str) -> Self {
+    /// The groups of all the matches of the pattern are numbered in a row, e.g. "(\w+),?" captures
+    /// every word of "a,b,c" as `{1}`, `{2}` and `{3}`. With `single_match` only the groups of the
+    /// first match are used. Named groups are taken from the last match that captured them.
+    /// Groups that didn't participate in a match are left out, unless `keep_unmatched` is set.
+    /// The captures of every match are kept as well, for `{match.2.1}`.
+    fn builder(
+        pattern: &'a Regex,
+        content: &'a str,
+        single_match: bool,
+        keep_unmatched: bool,
+    ) -> Self {
         let mut map = HashMap::new();
         map.insert("".to_string(), Cow::Borrowed(content));
         map.insert("0".to_string(), Cow::Borrowed(content));
@@ -259,26 +2813,199 @@ impl<'a> RegexContext<'a> {
         let group_names = pattern.capture_names().flatten().collect::<Vec<&str>>();
 
         let mut groups = vec![];
+        let mut offsets = HashMap::new();
 
-        for caps in pattern.captures_iter(content) {
+        let mut matches = vec![];
+        let max_matches = if single_match { 1 } else { usize::MAX };
+        for caps in pattern.captures_iter(content).take(max_matches) {
             // the numbered group
-            for mat in caps.iter().skip(1).flatten() {
-                groups.push(Cow::Borrowed(mat.as_str()));
+            for mat in caps.iter().skip(1) {
+                match mat {
+                    Some(mat) => {
+                        groups.push(Cow::Borrowed(mat.as_str()));
+                        offsets.insert(groups.len().to_string(), mat.start());
+                    }
+                    None if keep_unmatched => groups.push(Cow::Borrowed("")),
+                    None => {}
+                }
             }
 
             // the named group
             for name in group_names.iter() {
                 if let Some(mat) = caps.name(name) {
                     map.insert(name.to_string(), Cow::Borrowed(mat.as_str()));
+                    offsets.insert(name.to_string(), mat.start());
                 }
             }
+            matches.push(caps);
+        }
+
+        RegexContext {
+            map,
+            groups,
+            offsets,
+            default_sep: Cow::Borrowed(" "),
+            output_sep: Cow::Borrowed(" "),
+            ci_names: false,
+            matches,
+        }
+    }
+
+    /// The fields between the matches of the delimiter, empty ones included, e.g. "a,,b," is split
+    /// on "," into "a", "", "b" and ""
+    fn from_split(delimiter: &Regex, content: &'a str) -> Self {
+        let mut groups = vec![];
+        let mut offsets = HashMap::new();
+        let mut start = 0;
+        for mat in delimiter.find_iter(content) {
+            groups.push(Cow::Borrowed(&content[start..mat.start()]));
+            offsets.insert(groups.len().to_string(), start);
+            start = mat.end();
+        }
+        groups.push(Cow::Borrowed(&content[start..]));
+        offsets.insert(groups.len().to_string(), start);
+
+        RegexContext {
+            offsets,
+            ..RegexContext::from_groups(content, groups)
+        }
+    }
+
+    /// The context of input that is already split into groups
+    fn from_groups(content: &'a str, groups: Vec<Cow<'a, str>>) -> Self {
+        let mut map = HashMap::new();
+        map.insert("".to_string(), Cow::Borrowed(content));
+        map.insert("0".to_string(), Cow::Borrowed(content));
+
+        RegexContext {
+            map,
+            groups,
+            offsets: HashMap::new(),
+            default_sep: Cow::Borrowed(" "),
+            output_sep: Cow::Borrowed(" "),
+            ci_names: false,
+            matches: vec![],
+        }
+    }
+
+    /// The numbered and named groups and the special variables to export with `--env`. Names
+    /// that are not made of word characters, like header fields with spaces, are left out.
+    fn variables(&self) -> Vec<(String, String)> {
+        let numbered = self
+            .groups
+            .iter()
+            .enumerate()
+            .map(|(idx, group)| ((idx + 1).to_string(), group.to_string()));
+        let named = self
+            .map
+            .iter()
+            .filter(|(name, _)| is_variable_name(name))
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+        numbered.chain(named).collect()
+    }
+
+    /// e.g. `{"fields":["a","b"],"groups":{"name":"a"},"linenum":1}`, the named groups are the
+    /// ones of the pattern or the header, without the special variables
+    fn to_json(&self, line_num: i32) -> Value {
+        let named = self
+            .map
+            .iter()
+            .filter(|(name, _)| {
+                is_variable_name(name)
+                    && !name.bytes().all(|b| b.is_ascii_digit())
+                    && !SPECIAL_KEYS.contains(&name.as_str())
+            })
+            .map(|(name, value)| (name.to_string(), Value::from(value.as_ref())))
+            .collect::<serde_json::Map<_, _>>();
+        serde_json::json!({
+            "linenum": line_num,
+            "fields": self.groups,
+            "groups": named,
+        })
+    }
+
+    /// Take the named groups of an other context of the same content, the numbered groups stay
+    fn names_from(mut self, other: RegexContext<'a>) -> Self {
+        for (name, value) in other.map {
+            if !name.is_empty() && name != "0" {
+                self.map.insert(name, value);
+            }
+        }
+        for (name, offset) in other.offsets {
+            if !name.starts_with(|ch: char| ch.is_ascii_digit()) {
+                self.offsets.insert(name, offset);
+            }
+        }
+        self.matches = other.matches;
+        self
+    }
+
+    /// Add empty groups up to `min_groups`, longer lists of groups are kept as they are
+    fn pad_groups(mut self, min_groups: usize) -> Self {
+        if self.groups.len() < min_groups {
+            self.groups.resize(min_groups, Cow::Borrowed(""));
+        }
+        self
+    }
+
+    /// With `--ci-names`, store the names of the groups in lowercase, so that they are looked up
+    /// ignoring case. The special variables are put afterwards and keep their case.
+    fn fold_names(mut self, ci_names: bool) -> Self {
+        if ci_names {
+            self.map = self
+                .map
+                .into_iter()
+                .map(|(name, value)| (name.to_lowercase(), value))
+                .collect();
+            self.offsets = self
+                .offsets
+                .into_iter()
+                .map(|(name, offset)| (name.to_lowercase(), offset))
+                .collect();
+            self.ci_names = true;
+        }
+        self
+    }
+
+    /// Exactly, or in lowercase with `--ci-names`
+    fn lookup<'m, V>(&self, map: &'m HashMap<String, V>, name: &str) -> Option<&'m V> {
+        map.get(name).or_else(|| {
+            if self.ci_names {
+                map.get(&name.to_lowercase())
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Trim the numbered and named groups, the whole content stays as it is
+    fn trim_groups(mut self, trim: Option<&Trim>) -> Self {
+        let trim = match trim {
+            Some(trim) => trim,
+            None => return self,
+        };
+        let apply = |value: Cow<'a, str>| match value {
+            Cow::Borrowed(value) => Cow::Borrowed(trim.apply(value)),
+            Cow::Owned(value) => Cow::Owned(trim.apply(&value).to_string()),
+        };
+
+        self.groups = self.groups.into_iter().map(apply).collect();
+        for (name, value) in self.map.iter_mut() {
+            if !name.is_empty() && name != "0" {
+                *value = apply(std::mem::take(value));
+            }
         }
+        self
+    }
 
-        RegexContext {
-            map,
-            groups,
-            default_sep: Cow::Borrowed(" "),
+    /// Name the numbered groups, names of groups missing in the input stay unmatched
+    pub fn name_groups(mut self, names: &HashMap<String, usize>) -> Self {
+        for (name, &idx) in names {
+            if let Some(group) = self.groups.get(idx - 1) {
+                self.map.insert(name.to_string(), group.clone());
+            }
         }
+        self
     }
 
     pub fn default_sep(mut self, default_sep: Cow<'a, str>) -> Self {
@@ -286,17 +3013,48 @@ impl<'a> RegexContext<'a> {
         self
     }
 
+    pub fn output_sep(mut self, output_sep: Cow<'a, str>) -> Self {
+        self.output_sep = output_sep;
+        self
+    }
+
     pub fn put(mut self, key: &str, value: Cow<'a, str>) -> Self {
         self.map.insert(key.to_string(), value);
         self
     }
 
+    /// The number of groups is only known once they are all captured
     pub fn build(self) -> Self {
-        self
+        let count = self.groups.len().to_string();
+        self.put(CONTEXT_KEY_GROUP_COUNT, Cow::Owned(count))
     }
 
-    fn translate_neg_index(&self, idx: i32) -> usize {
-        let len = self.groups.len() as i32;
+    /// `{@name}` and `{@1}` are where the group starts in the line, in bytes
+    fn get_offset(&self, group_name: &str) -> Option<Cow<'a, str>> {
+        match group_name {
+            "0" => Some(Cow::Borrowed("0")),
+            _ => self
+                .lookup(&self.offsets, group_name)
+                .map(|offset| Cow::Owned(offset.to_string())),
+        }
+    }
+
+    /// `{match.2.1}` is the first group of the second match, `{match.2.name}` its named group
+    /// and `{match.2.0}` the whole match
+    fn get_match_group(&self, group_name: &str) -> Option<Cow<'a, str>> {
+        let (idx, group) = group_name.strip_prefix(MATCH_PREFIX)?.split_once('.')?;
+        let caps = self
+            .matches
+            .get(idx.parse::<usize>().ok()?.checked_sub(1)?)?;
+        let mat = match group.parse::<usize>() {
+            Ok(num) => caps.get(num),
+            Err(_) => caps.name(group),
+        }?;
+        Some(Cow::Borrowed(mat.as_str()))
+    }
+
+    fn translate_neg_index(&self, idx: i64) -> usize {
+        let len = self.groups.len() as i64;
         let idx = if idx < 0 { idx + len + 1 } else { idx };
         max(0, idx) as usize
     }
@@ -304,7 +3062,13 @@ impl<'a> RegexContext<'a> {
 
 impl<'a> Context<'a> for RegexContext<'a> {
     fn get_by_name(&'a self, group_name: &str) -> Option<Cow<'a, str>> {
-        self.map.get(group_name).cloned()
+        match group_name.strip_prefix('@') {
+            Some(group_name) => self.get_offset(group_name),
+            None => self
+                .lookup(&self.map, group_name)
+                .cloned()
+                .or_else(|| self.get_match_group(group_name)),
+        }
     }
 
     fn get_by_range(&'a self, range: &Range, sep: Option<&str>) -> Option<Cow<'a, str>> {
@@ -326,15 +3090,18 @@ impl<'a> Context<'a> for RegexContext<'a> {
                 let right = self.translate_neg_index(right);
 
                 if left == 0 {
-                    return self.get_by_range(&LeftInf(right as i32), sep);
+                    return self.get_by_range(&LeftInf(right as i64), sep);
                 } else if right > self.groups.len() {
-                    return self.get_by_range(&RightInf(left as i32), sep);
+                    return self.get_by_range(&RightInf(left as i64), sep);
                 } else if left == right {
-                    return self.get_by_range(&Single(left as i32), sep);
+                    return self.get_by_range(&Single(left as i64), sep);
                 }
 
                 Some(Cow::Owned(
-                    self.groups[(left - 1)..right].join(sep.unwrap_or(&self.default_sep)),
+                    self.groups
+                        .get((left - 1)..right)
+                        .unwrap_or_default()
+                        .join(sep.unwrap_or(&self.default_sep)),
                 ))
             }
 
@@ -356,7 +3123,11 @@ impl<'a> Context<'a> for RegexContext<'a> {
                 }
 
                 Some(Cow::Owned(
-                    self.groups[(left - 1)..].join(sep.unwrap_or(&self.default_sep)),
+                    // empty if it starts past the last field
+                    self.groups
+                        .get((left - 1)..)
+                        .unwrap_or_default()
+                        .join(sep.unwrap_or(&self.default_sep)),
                 ))
             }
 
@@ -385,14 +3156,17 @@ impl<'a> Context<'a> for RegexContext<'a> {
                 let right = self.translate_neg_index(right);
 
                 if left == 0 {
-                    return self.get_by_split_range(&LeftInf(right as i32));
+                    return self.get_by_split_range(&LeftInf(right as i64));
                 } else if right > self.groups.len() {
-                    return self.get_by_split_range(&RightInf(left as i32));
+                    return self.get_by_split_range(&RightInf(left as i64));
                 } else if left == right {
-                    return self.get_by_split_range(&Single(left as i32));
+                    return self.get_by_split_range(&Single(left as i64));
                 }
 
-                self.groups[(left - 1)..right].to_vec()
+                self.groups
+                    .get((left - 1)..right)
+                    .unwrap_or_default()
+                    .to_vec()
             }
 
             LeftInf(right) => {
@@ -410,89 +3184,506 @@ impl<'a> Context<'a> for RegexContext<'a> {
                     return self.get_by_split_range(&Inf());
                 }
 
-                self.groups[(left - 1)..].to_vec()
+                self.groups.get((left - 1)..).unwrap_or_default().to_vec()
             }
 
             Inf() => self.groups.to_vec(),
         }
     }
+
+    fn output_separator(&'a self) -> &'a str {
+        &self.output_sep
+    }
+}
+
+/// The context parsed from a line of JSON. For Example:
+///
+/// input: {"user": "alice", "address": {"city": "Paris"}, "tags": ["a", "b"]}
+///
+/// will result in the context:
+/// {}/{0} => the whole line
+/// {user} => "alice"
+/// {address.city} => "Paris"
+/// {tags.0} => "a"
+/// {tags} => "[\"a\",\"b\"]"
+///
+/// If the line is an array, its elements are the numbered groups instead.
+struct JsonContext<'a> {
+    value: Value,
+    fields: RegexContext<'a>, // the special variables and the elements of a top level array
+}
+
+impl JsonContext<'_> {
+    /// The top level keys of an object come first, so that the special variables override them
+    fn variables(&self) -> Vec<(String, String)> {
+        let mut variables = match &self.value {
+            Value::Object(map) => map
+                .iter()
+                .filter(|(key, _)| is_variable_name(key))
+                .filter_map(|(key, value)| {
+                    Some((key.to_string(), json_to_str(value)?.into_owned()))
+                })
+                .collect(),
+            _ => vec![],
+        };
+        variables.extend(self.fields.variables());
+        variables
+    }
+}
+
+impl<'a> Context<'a> for JsonContext<'a> {
+    fn get_by_name(&'a self, group_name: &str) -> Option<Cow<'a, str>> {
+        self.fields.get_by_name(group_name).or_else(|| {
+            group_name
+                .split('.')
+                .try_fold(&self.value, |value, key| match value {
+                    Value::Object(map) => map.get(key),
+                    Value::Array(array) => array.get(key.parse::<usize>().ok()?),
+                    _ => None,
+                })
+                .and_then(json_to_str)
+        })
+    }
+
+    fn get_by_range(&'a self, range: &Range, sep: Option<&str>) -> Option<Cow<'a, str>> {
+        self.fields.get_by_range(range, sep)
+    }
+
+    fn get_by_split_range(&'a self, range: &Range) -> Vec<Cow<'a, str>> {
+        self.fields.get_by_split_range(range)
+    }
+
+    fn output_separator(&'a self) -> &'a str {
+        self.fields.output_separator()
+    }
+}
+
+/// Strings are substituted without quotes, other values as JSON. `null` counts as missing.
+fn json_to_str(value: &Value) -> Option<Cow<'_, str>> {
+    match value {
+        Value::Null => None,
+        Value::String(s) => Some(Cow::Borrowed(s)),
+        _ => Some(Cow::Owned(value.to_string())),
+    }
+}
+
+fn json_groups(value: &Value) -> Vec<Cow<'static, str>> {
+    match value {
+        Value::Array(array) => array
+            .iter()
+            .map(|value| Cow::Owned(json_to_str(value).unwrap_or_default().into_owned()))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+enum InputContext<'a> {
+    Regex(RegexContext<'a>),
+    Json(JsonContext<'a>),
+}
+
+impl InputContext<'_> {
+    fn variables(&self) -> Vec<(String, String)> {
+        match self {
+            InputContext::Regex(context) => context.variables(),
+            InputContext::Json(context) => context.variables(),
+        }
+    }
+}
+
+fn is_variable_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+}
+
+impl<'a> Context<'a> for InputContext<'a> {
+    fn get_by_name(&'a self, group_name: &str) -> Option<Cow<'a, str>> {
+        match self {
+            InputContext::Regex(context) => context.get_by_name(group_name),
+            InputContext::Json(context) => context.get_by_name(group_name),
+        }
+    }
+
+    fn get_by_range(&'a self, range: &Range, sep: Option<&str>) -> Option<Cow<'a, str>> {
+        match self {
+            InputContext::Regex(context) => context.get_by_range(range, sep),
+            InputContext::Json(context) => context.get_by_range(range, sep),
+        }
+    }
+
+    fn get_by_split_range(&'a self, range: &Range) -> Vec<Cow<'a, str>> {
+        match self {
+            InputContext::Regex(context) => context.get_by_split_range(range),
+            InputContext::Json(context) => context.get_by_split_range(range),
+        }
+    }
+
+    fn output_separator(&'a self) -> &'a str {
+        match self {
+            InputContext::Regex(context) => context.output_separator(),
+            InputContext::Json(context) => context.output_separator(),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 enum Range {
-    Single(i32),
-    Both(i32, i32),
-    LeftInf(i32),
-    RightInf(i32),
+    Single(i64),
+    Both(i64, i64),
+    LeftInf(i64),
+    RightInf(i64),
     Inf(),
 }
 
 use Range::*;
 
+impl fmt::Display for Range {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Single(num) => write!(f, "{}", num),
+            Both(left, right) => write!(f, "{}..{}", left, right),
+            LeftInf(right) => write!(f, "..{}", right),
+            RightInf(left) => write!(f, "{}..", left),
+            Inf() => write!(f, ".."),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 enum ArgFragment {
     Literal(String),
-    NamedGroup(String),
-    RangeGroup(Range, Option<String>),
+    NamedGroup(String, Option<String>), // name, default
+    EnvVar(String, Option<String>),     // name, default
+    RangeGroup(Range, Option<String>, Option<String>), // range, separator, default
     SplitRangeGroup(Range),
+    Filtered(Box<ArgFragment>, Vec<Filter>, String), // field, filters, their specs as written
+    Conditional(Box<ArgFragment>, Vec<ArgFragment>, Vec<ArgFragment>), // field, present, absent
 }
 
 use ArgFragment::*;
 
 impl ArgFragment {
-    fn parse(field_string: &str) -> Self {
-        let opt_caps = FIELD_SINGLE.captures(field_string);
+    /// Parse a field followed by an optional chain of filters, e.g. `{1|trim|upper}`
+    fn parse(field_string: &str, syntax: &FieldSyntax) -> Result<Self, String> {
+        if let Some(caps) = syntax.conditional.captures(field_string) {
+            let name = &caps["name"];
+            let field = ArgFragment::parse_field(
+                &format!("{}{}{}", syntax.open, name, syntax.close),
+                syntax,
+            )?;
+            let (present, absent) = split_branches(&caps["body"], syntax);
+            return Ok(Conditional(
+                Box::new(field),
+                parse_branch(present, syntax)?,
+                parse_branch(absent, syntax)?,
+            ));
+        }
+
+        // `{id:-{LN}}` is the same as `{id?{id}:{LN}}`, the fields of the default can't have
+        // defaults with fields themselves
+        if let Some(caps) = syntax.nested_default.captures(field_string) {
+            let field = ArgFragment::parse_field(
+                &format!("{}{}{}", syntax.open, &caps["field"], syntax.close),
+                syntax,
+            )?;
+            if !matches!(field, Literal(_) | SplitRangeGroup(_)) {
+                return Ok(Conditional(
+                    Box::new(field.clone()),
+                    vec![field],
+                    parse_branch(&caps["default"], syntax)?,
+                ));
+            }
+        }
+
+        if let Some(pos) = field_string.find('|') {
+            let selector = format!("{}{}", &field_string[..pos], syntax.close);
+            let field = ArgFragment::parse_field(&selector, syntax)?;
+            if !matches!(field, Literal(_)) {
+                let specs = &field_string[(pos + 1)..(field_string.len() - syntax.close.len())];
+                let filters = specs
+                    .split('|')
+                    .map(|spec| Filter::parse(spec, syntax.ignore_case))
+                    .collect();
+                match filters {
+                    Ok(filters) => {
+                        return Ok(Filtered(Box::new(field), filters, specs.to_string()))
+                    }
+                    // `|` may as well be the separator of a range, e.g. `{..:|}`
+                    Err(error) => match ArgFragment::parse_field(field_string, syntax)? {
+                        Literal(_) => return Err(error),
+                        field => return Ok(field),
+                    },
+                }
+            }
+        }
+
+        ArgFragment::parse_field(field_string, syntax)
+    }
+
+    /// Print the fragment back in the template syntax with the delimiters of the template, e.g.
+    /// for --check and error messages
+    fn to_template(&self, open: &str, close: &str) -> String {
+        let default = |default: &Option<String>| {
+            default
+                .as_ref()
+                .map_or(String::new(), |default| format!(":-{}", default))
+        };
+        let field = |content: String| format!("{}{}{}", open, content, close);
+        // the content of a field, to be continued with a filter chain or a conditional
+        let unclosed = |fragment: &ArgFragment| {
+            let field = fragment.to_template(open, close);
+            match field.strip_suffix(close) {
+                Some(content) => content.to_string(),
+                None => field,
+            }
+        };
+        let branch = |fragments: &[ArgFragment]| {
+            fragments
+                .iter()
+                .map(|fragment| fragment.to_template(open, close))
+                .collect::<String>()
+        };
+        match self {
+            Literal(literal) => literal.clone(),
+            NamedGroup(name, value) => field(format!("{}{}", name, default(value))),
+            EnvVar(name, value) => field(format!("${}{}", name, default(value))),
+            RangeGroup(range, sep, value) => field(format!(
+                "{}{}{}",
+                range,
+                sep.as_ref()
+                    .map_or(String::new(), |sep| format!(":{}", sep)),
+                default(value)
+            )),
+            SplitRangeGroup(range) => field(range.to_string().replace("..", "...")),
+            Filtered(inner, _, specs) => format!("{}|{}{}", unclosed(inner), specs, close),
+            // `{id:-{LN}}` is parsed as `{id?{id}:{LN}}`
+            Conditional(inner, present, absent)
+                if !absent.is_empty()
+                    && present.len() == 1
+                    && present[0].to_template(open, close) == inner.to_template(open, close) =>
+            {
+                format!("{}:-{}{}", unclosed(inner), branch(absent), close)
+            }
+            Conditional(inner, present, absent) => {
+                let absent = branch(absent);
+                format!(
+                    "{}?{}{}{}{}",
+                    unclosed(inner),
+                    branch(present),
+                    if absent.is_empty() { "" } else { ":" },
+                    absent,
+                    close
+                )
+            }
+        }
+    }
+
+    /// Fails only if a field index doesn't fit in an `i64`, other malformed fields are literals
+    fn parse_field(field_string: &str, syntax: &FieldSyntax) -> Result<Self, String> {
+        // the regexes only match digits, a bound without any is open, so that it's printed back
+        // as it was written, e.g. by --check
+        let index = |index: Option<regex::Match>| match index {
+            Some(index) if index.as_str().trim_start_matches('-').is_empty() => Ok(None),
+            Some(index) => index
+                .as_str()
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("invalid field index: {}", field_string)),
+            None => Ok(None),
+        };
+
+        let opt_caps = syntax.single.captures(field_string);
         if let Some(caps) = opt_caps {
-            return RangeGroup(
+            return Ok(RangeGroup(
                 Single(
-                    caps.name("num")
-                        .expect("something is wrong in matching FIELD_SINGLE")
-                        .as_str()
-                        .parse()
-                        .expect("field is not a number"),
+                    index(caps.name("num"))?.expect("something is wrong in matching FIELD_SINGLE"),
                 ),
                 None,
-            );
+                caps.name("default").map(|s| s.as_str().to_string()),
+            ));
         }
 
-        let opt_caps = FIELD_NAMED.captures(field_string);
+        let opt_caps = syntax.named.captures(field_string);
         if let Some(caps) = opt_caps {
-            return NamedGroup(
+            return Ok(NamedGroup(
                 caps.name("name")
                     .expect("something is wrong in matching FIELD_NAMED")
                     .as_str()
                     .to_string(),
-            );
+                caps.name("default").map(|s| s.as_str().to_string()),
+            ));
         }
 
-        let opt_caps = FIELD_RANGE.captures(field_string);
+        let opt_caps = syntax.env.captures(field_string);
         if let Some(caps) = opt_caps {
-            let opt_left = caps.name("left").map(|s| s.as_str().parse().unwrap_or(1));
-            let opt_right = caps.name("right").map(|s| s.as_str().parse().unwrap_or(-1));
-            let opt_sep = caps.name("sep").map(|s| s.as_str().to_string());
-
-            return match (opt_left, opt_right) {
-                (None, None) => RangeGroup(Inf(), opt_sep),
-                (None, Some(right)) => RangeGroup(LeftInf(right), opt_sep),
-                (Some(left), None) => RangeGroup(RightInf(left), opt_sep),
-                (Some(left), Some(right)) => RangeGroup(Both(left, right), opt_sep),
-            };
+            return Ok(EnvVar(
+                caps.name("name")
+                    .expect("something is wrong in matching FIELD_ENV")
+                    .as_str()
+                    .to_string(),
+                caps.name("default").map(|s| s.as_str().to_string()),
+            ));
+        }
+
+        let opt_caps = syntax.range.captures(field_string);
+        if let Some(caps) = opt_caps {
+            let opt_left = index(caps.name("left"))?;
+            let opt_right = index(caps.name("right"))?;
+            let opt_sep = caps.name("sep").map(|s| unescape(s.as_str()));
+
+            return Ok(match (opt_left, opt_right) {
+                (None, None) => RangeGroup(Inf(), opt_sep, None),
+                (None, Some(right)) => RangeGroup(LeftInf(right), opt_sep, None),
+                (Some(left), None) => RangeGroup(RightInf(left), opt_sep, None),
+                (Some(left), Some(right)) => RangeGroup(Both(left, right), opt_sep, None),
+            });
         }
 
-        let opt_caps = FIELD_SPLIT_RANGE.captures(field_string);
+        let opt_caps = syntax.split_range.captures(field_string);
         if let Some(caps) = opt_caps {
-            let opt_left = caps.name("left").map(|s| s.as_str().parse().unwrap_or(1));
-            let opt_right = caps.name("right").map(|s| s.as_str().parse().unwrap_or(-1));
+            let opt_left = index(caps.name("left"))?;
+            let opt_right = index(caps.name("right"))?;
 
-            return match (opt_left, opt_right) {
+            return Ok(match (opt_left, opt_right) {
                 (None, None) => SplitRangeGroup(Inf()),
                 (None, Some(right)) => SplitRangeGroup(LeftInf(right)),
                 (Some(left), None) => SplitRangeGroup(RightInf(left)),
                 (Some(left), Some(right)) => SplitRangeGroup(Both(left, right)),
-            };
+            });
+        }
+
+        Ok(Literal(field_string.to_string()))
+    }
+
+    /// Expand a field that is joined into a single argument
+    fn expand<'a, T: Context<'a>>(&'a self, context: &'a T) -> Option<Cow<'a, str>> {
+        match self {
+            Literal(ref literal) => Some(Cow::Borrowed(literal.as_str())),
+            NamedGroup(ref name, ref default) => or_default(context.get_by_name(name), default),
+            EnvVar(ref name, ref default) => {
+                or_default(std::env::var(name).ok().map(Cow::Owned), default)
+            }
+            RangeGroup(ref range, ref opt_sep, ref default) => or_default(
+                context.get_by_range(range, opt_sep.as_ref().map(String::as_str)),
+                default,
+            ),
+            Filtered(ref field, ref filters, _) => {
+                let values = field.expand(context).into_iter().collect();
+                let mut values = filters.iter().fold(values, |values, f| f.apply_all(values));
+                // the values split by `on` are joined again
+                match values.len() {
+                    0 | 1 => values.pop(),
+                    _ => Some(Cow::Owned(values.join(context.output_separator()))),
+                }
+            }
+            Conditional(ref field, ref present, ref absent) => {
+                let branch = if field.is_blank_in(context) {
+                    absent
+                } else {
+                    present
+                };
+                let value = branch
+                    .iter()
+                    .filter_map(|fragment| fragment.expand(context))
+                    .collect::<String>();
+                Some(Cow::Owned(value))
+            }
+            SplitRangeGroup(_) => unreachable!("split ranges are never joined"),
+        }
+    }
+
+    /// What the field expands to for `--check`, `None` for literals
+    fn describe(&self) -> Option<String> {
+        let with_default = |description: String, default: &Option<String>| match default {
+            Some(default) => format!("{}, default {:?}", description, default),
+            None => description,
+        };
+        let description = match self {
+            Literal(_) => return None,
+            NamedGroup(name, default) => {
+                let description =
+                    if SPECIAL_KEYS.contains(&name.as_str()) || name == CONTEXT_KEY_GROUP_COUNT {
+                        format!("special variable {}", name)
+                    } else if let Some(name) = name.strip_prefix(PREV_PREFIX) {
+                        format!("named field {} of the previous line", name)
+                    } else if let Some(name) = name.strip_prefix('@') {
+                        format!("offset of named field {}", name)
+                    } else if name.is_empty() {
+                        "whole line".to_string()
+                    } else {
+                        format!("named field {}", name)
+                    };
+                with_default(description, default)
+            }
+            EnvVar(name, default) => {
+                with_default(format!("environment variable {}", name), default)
+            }
+            RangeGroup(Single(num), _, default) => {
+                with_default(format!("numbered field {}", num), default)
+            }
+            RangeGroup(range, sep, _) => match sep {
+                Some(sep) => format!("range {} joined by {:?}", range, sep),
+                None => format!("range {} joined by the separator", range),
+            },
+            SplitRangeGroup(range) => format!("range {} split into arguments", range),
+            Filtered(field, filters, _) => format!(
+                "{}, filtered by {}",
+                field.describe()?,
+                filters
+                    .iter()
+                    .map(Filter::name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Conditional(field, _, _) => format!("conditional on {}", field.describe()?),
+        };
+        Some(description)
+    }
+
+    /// The fields that parsed as literals, e.g. `{1..x}`, also in the branches of conditionals
+    fn malformed<'a>(&'a self, open: &str, close: &str) -> Vec<&'a str> {
+        match self {
+            Literal(literal)
+                if literal.len() >= open.len() + close.len()
+                    && literal.starts_with(open)
+                    && literal.ends_with(close) =>
+            {
+                vec![literal.as_str()]
+            }
+            Filtered(field, _, _) => field.malformed(open, close),
+            Conditional(field, present, absent) => std::iter::once(field.as_ref())
+                .chain(present)
+                .chain(absent)
+                .flat_map(|fragment| fragment.malformed(open, close))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn uses_prev(&self) -> bool {
+        match self {
+            NamedGroup(name, _) => name.starts_with(PREV_PREFIX),
+            Filtered(field, _, _) => field.uses_prev(),
+            Conditional(field, present, absent) => {
+                field.uses_prev() || present.iter().chain(absent).any(ArgFragment::uses_prev)
+            }
+            _ => false,
         }
+    }
 
-        Literal(field_string.to_string())
+    /// Literals are blank if they are whitespace only, fields if they are unmatched or empty
+    fn is_blank_in<'a, T: Context<'a>>(&'a self, context: &'a T) -> bool {
+        match self {
+            Literal(literal) => literal.trim().is_empty(),
+            SplitRangeGroup(range) => context
+                .get_by_split_range(range)
+                .iter()
+                .all(|value| value.is_empty()),
+            Filtered(field, _, _) => field.is_blank_in(context),
+            _ => self.expand(context).is_none_or(|value| value.is_empty()),
+        }
     }
 }
 
@@ -501,134 +3692,403 @@ impl ArgFragment {
 /// "x {abc} z" will be compiled so that later `{abc}` could be replaced by actuals content
 #[derive(Debug)]
 struct ArgTemplate {
+    source: String,               // as written, for --check
+    delimiters: (String, String), // to print the fields back
     fragments: Vec<ArgFragment>,
+    combinations: Vec<Combination>,
+    quote: bool,
+    strict: bool,
 }
 
-impl<'a> From<&'a str> for ArgTemplate {
-    fn from(arg: &'a str) -> Self {
-        let mut fragments = Vec::new();
-        let mut last = 0;
-        for mat in CMD_REGEX.find_iter(arg) {
-            fragments.push(Literal(arg[last..mat.start()].to_string()));
-            fragments.push(ArgFragment::parse(mat.as_str()));
-            last = mat.end()
+/// Split the body of a conditional at the first `:` that is not inside a field
+fn split_branches<'a>(body: &'a str, syntax: &FieldSyntax) -> (&'a str, &'a str) {
+    let mut depth = 0;
+    let mut pos = 0;
+    while pos < body.len() {
+        let rest = &body[pos..];
+        if rest.starts_with(&syntax.open) {
+            depth += 1;
+            pos += syntax.open.len();
+        } else if rest.starts_with(&syntax.close) {
+            depth -= 1;
+            pos += syntax.close.len();
+        } else if depth == 0 && rest.starts_with(':') {
+            return (&body[..pos], &body[(pos + 1)..]);
+        } else {
+            pos += rest.chars().next().map_or(1, char::len_utf8);
         }
-        fragments.push(ArgFragment::Literal(arg[last..].to_string()));
+    }
+    (body, "")
+}
 
-        ArgTemplate { fragments }
+/// The branches of a conditional are joined into one value, so they can't split ranges
+fn parse_branch(branch: &str, syntax: &FieldSyntax) -> Result<Vec<ArgFragment>, String> {
+    let fragments = parse_fragments(branch, syntax)?;
+    let is_split = |fragment: &ArgFragment| match fragment {
+        SplitRangeGroup(_) => true,
+        Filtered(field, _, _) => matches!(**field, SplitRangeGroup(_)),
+        _ => false,
+    };
+    match fragments.iter().find(|fragment| is_split(fragment)) {
+        Some(split) => Err(format!(
+            "split range {} not allowed in a conditional",
+            split.to_template(&syntax.open, &syntax.close)
+        )),
+        None => Ok(fragments),
     }
 }
 
-#[derive(Debug, Clone)]
-enum Join {
-    Literal(String),
-    NamedGroup(String),
-    RangeGroup(Range, Option<String>),
+fn parse_fragments(arg: &str, syntax: &FieldSyntax) -> Result<Vec<ArgFragment>, String> {
+    let escaped_open = syntax.open.repeat(2);
+    let escaped_close = syntax.close.repeat(2);
+    let mut fragments = Vec::new();
+    let mut last = 0;
+    // the start of a default or conditional before the last field, e.g. `{id:-` of
+    // `{id:-{nope:-{LN}}}`, which is only one if it's closed right after the field
+    let mut opened_at = None;
+    let too_deep = |start: usize, end: usize| {
+        format!(
+            "invalid field: {}{}, the fields in a default or conditional can't contain fields themselves",
+            &arg[start..end],
+            syntax.close
+        )
+    };
+    for mat in syntax.cmd.find_iter(arg) {
+        let literal = &arg[last..mat.start()];
+        if let Some(start) = opened_at.take() {
+            if literal.starts_with(&syntax.close) {
+                return Err(too_deep(start, last));
+            }
+        }
+        fragments.push(Literal(literal.to_string()));
+        if mat.as_str() == escaped_open {
+            fragments.push(Literal(syntax.open.clone()));
+        } else if mat.as_str() == escaped_close {
+            fragments.push(Literal(syntax.close.clone()));
+        } else {
+            opened_at = syntax
+                .unclosed
+                .find(literal)
+                .map(|open| last + open.start());
+            fragments.push(ArgFragment::parse(mat.as_str(), syntax)?);
+        }
+        last = mat.end()
+    }
+    if let Some(start) = opened_at {
+        if arg[last..].starts_with(&syntax.close) {
+            return Err(too_deep(start, last));
+        }
+    }
+    fragments.push(ArgFragment::Literal(arg[last..].to_string()));
+    Ok(fragments)
 }
 
-#[derive(Debug, Clone)]
-struct Split(Range);
+impl ArgTemplate {
+    fn parse(arg: &str, syntax: &FieldSyntax) -> Result<Self, String> {
+        let fragments = parse_fragments(arg, syntax)?;
+
+        Ok(ArgTemplate {
+            source: arg.to_string(),
+            delimiters: (syntax.open.clone(), syntax.close.clone()),
+            combinations: group_combinations(fragments.iter(), false),
+            fragments,
+            quote: false,
+            strict: false,
+        })
+    }
+}
 
 #[derive(Debug, Clone)]
 enum Combination {
-    Join(Vec<Join>),
-    Split(Split),
+    Join(Vec<ArgFragment>),
+    /// With `--each`, the prefix and suffix are joined to every element of the split
+    Split {
+        range: Range,
+        filters: Vec<Filter>,
+        prefix: Vec<ArgFragment>,
+        suffix: Vec<ArgFragment>,
+    },
+    /// A conditional that is a whole argument, its present branch is split into words like by a
+    /// shell, e.g. `{user?--user {user}}` is two arguments
+    Words {
+        field: ArgFragment,
+        present: Vec<Vec<ArgFragment>>,
+        absent: Vec<ArgFragment>,
+    },
 }
 
-impl<'a> ArgTemplate {
-    fn apply_context<T: Context<'a>>(&self, context: &'a T) -> Vec<String> {
-        let combinations = group_combinations(self.fragments.iter());
-        combine_with_context(context, combinations.iter())
+impl ArgTemplate {
+    /// Shell-quote the substituted fields, literal parts of the template are kept as is
+    fn quote(mut self, quote: bool) -> Self {
+        self.quote = quote;
+        self
+    }
+
+    /// Treat fields missing in the context as errors instead of expanding them to nothing
+    fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Distribute the text surrounding a split range to each of its elements
+    fn each(mut self, each: bool) -> Self {
+        self.combinations = group_combinations(self.fragments.iter(), each);
+        self
+    }
+
+    fn uses_prev(&self) -> bool {
+        self.fragments.iter().any(ArgFragment::uses_prev)
+    }
+
+    fn is_blank_in<'a, T: Context<'a>>(&'a self, context: &'a T) -> bool {
+        self.fragments
+            .iter()
+            .all(|fragment| fragment.is_blank_in(context))
+    }
+
+    /// Returns the missing field on error, which only happens in strict mode
+    fn apply_context<'a, T: Context<'a>>(&'a self, context: &'a T) -> Result<Vec<String>, String> {
+        combine_with_context(context, self.combinations.iter(), self.quote, self.strict)
+            .map_err(|field| field.to_template(&self.delimiters.0, &self.delimiters.1))
     }
 }
 
-/// Combine elements, splitting or joining the args as needed.
-fn combine_with_context<'a, 'b, T: Context<'a>>(
-    context: &'a T,
-    combinations: impl Iterator<Item = &'b Combination>,
-) -> Vec<String> {
-    combinations
-        .flat_map(|combination| match combination {
-            Combination::Join(joins) => {
-                let joined = joins
-                    .iter()
-                    .flat_map(|join| match join {
-                        Join::Literal(ref literal) => vec![Cow::Borrowed(literal.as_str())],
-                        Join::NamedGroup(ref name) => {
-                            context.get_by_name(name).map_or_else(Vec::new, |c| vec![c])
-                        }
-                        Join::RangeGroup(ref range, ref opt_sep) => context
-                            .get_by_range(range, opt_sep.as_ref().map(String::as_str))
-                            .map_or_else(Vec::new, |c| vec![c]),
-                    })
-                    .collect::<String>();
-                vec![joined]
+/// Quote the string so that a POSIX shell reads it back as a single word
+fn shell_quote(s: &str) -> Cow<'_, str> {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c);
+    if !s.is_empty() && s.chars().all(is_safe) {
+        return Cow::Borrowed(s);
+    }
+
+    Cow::Owned(format!("'{}'", s.replace('\'', r"'\''")))
+}
+
+/// Decode the escapes `\t`, `\n`, `\0` and `\\`, other backslashes are kept as they are
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(ch) = chars.next() {
+        let escaped = match (ch, chars.peek()) {
+            ('\\', Some('t')) => '\t',
+            ('\\', Some('n')) => '\n',
+            ('\\', Some('0')) => '\0',
+            ('\\', Some('\\')) => '\\',
+            _ => {
+                unescaped.push(ch);
+                continue;
             }
-            Combination::Split(Split(ref range)) => context
-                .get_by_split_range(range)
-                .iter()
-                .map(|s| s.as_ref().to_owned())
-                .collect::<Vec<String>>(),
-        })
-        .collect()
+        };
+        chars.next();
+        unescaped.push(escaped);
+    }
+    unescaped
 }
 
-/// Group the args by whether they should be split or joined in the output
-fn group_combinations<'a>(fragments: impl Iterator<Item = &'a ArgFragment>) -> Vec<Combination> {
-    fragments.fold(vec![], |mut acc: Vec<Combination>, e| {
-        let mut tail = match acc.pop() {
-            None => match e {
-                Literal(s) => {
-                    if s.is_empty() {
-                        vec![]
-                    } else {
-                        vec![Combination::Join(vec![Join::Literal(s.clone())])]
-                    }
-                }
-                NamedGroup(s) => vec![Combination::Join(vec![Join::NamedGroup(s.clone())])],
-                RangeGroup(r, s) => vec![Combination::Join(vec![Join::RangeGroup(
-                    r.clone(),
-                    s.clone(),
-                )])],
-                SplitRangeGroup(r) => vec![Combination::Split(Split(r.clone()))],
-            },
-            Some(last) => match (last, e) {
-                (last, SplitRangeGroup(r)) => {
-                    vec![last, Combination::Split(Split(r.clone()))]
-                }
-                (Combination::Join(mut joins), Literal(s)) => {
-                    joins.push(Join::Literal(s.clone()));
-                    vec![Combination::Join(joins)]
-                }
-                (Combination::Join(mut joins), NamedGroup(s)) => {
-                    joins.push(Join::NamedGroup(s.clone()));
-                    vec![Combination::Join(joins)]
-                }
-                (Combination::Join(mut joins), RangeGroup(r, s)) => {
-                    joins.push(Join::RangeGroup(r.clone(), s.clone()));
-                    vec![Combination::Join(joins)]
+fn quote_if(value: Cow<'_, str>, quote: bool) -> Cow<'_, str> {
+    if quote {
+        Cow::Owned(shell_quote(&value).into_owned())
+    } else {
+        value
+    }
+}
+
+/// Use the default if the field is missing or empty
+fn or_default<'a>(
+    value: Option<Cow<'a, str>>,
+    default: &'a Option<String>,
+) -> Option<Cow<'a, str>> {
+    match default {
+        Some(default) if value.as_ref().is_none_or(|v| v.is_empty()) => {
+            Some(Cow::Borrowed(default.as_str()))
+        }
+        _ => value,
+    }
+}
+
+/// Join the expanded fragments into a single argument
+fn expand_joins<'a, T: Context<'a>>(
+    context: &'a T,
+    joins: &'a [ArgFragment],
+    quote: bool,
+    strict: bool,
+) -> Result<String, &'a ArgFragment> {
+    let mut joined = String::new();
+    for join in joins {
+        if let Conditional(field, present, absent) = join {
+            let branch = if field.is_blank_in(context) {
+                absent
+            } else {
+                present
+            };
+            joined.push_str(&expand_joins(context, branch, quote, strict)?);
+            continue;
+        }
+
+        match join.expand(context) {
+            Some(value) if matches!(join, Literal(_)) => joined.push_str(&value),
+            Some(value) => joined.push_str(&quote_if(value, quote)),
+            None if strict => return Err(join),
+            None => {}
+        }
+    }
+    Ok(joined)
+}
+
+/// Combine elements, splitting or joining the args as needed.
+fn combine_with_context<'a, T: Context<'a>>(
+    context: &'a T,
+    combinations: impl Iterator<Item = &'a Combination>,
+    quote: bool,
+    strict: bool,
+) -> Result<Vec<String>, &'a ArgFragment> {
+    let mut args = vec![];
+    for combination in combinations {
+        match combination {
+            Combination::Join(joins) => {
+                let arg = expand_joins(context, joins, quote, strict)?;
+                // an argument made of conditionals only is left out if they expand to nothing
+                if arg.is_empty() && joins.iter().all(|join| matches!(join, Conditional(..))) {
+                    continue;
                 }
-                (last, Literal(s)) => {
-                    if s.is_empty() {
-                        vec![last]
-                    } else {
-                        vec![last, Combination::Join(vec![Join::Literal(s.clone())])]
+                args.push(arg);
+            }
+            Combination::Words {
+                field,
+                present,
+                absent,
+            } => {
+                if !field.is_blank_in(context) {
+                    for word in present {
+                        let word = expand_joins(context, word, quote, strict)?;
+                        if !word.is_empty() {
+                            args.push(word);
+                        }
                     }
+                    continue;
                 }
-                (last, NamedGroup(s)) => {
-                    vec![last, Combination::Join(vec![Join::NamedGroup(s.clone())])]
+                let arg = expand_joins(context, absent, quote, strict)?;
+                if !arg.is_empty() {
+                    args.push(arg);
                 }
-                (last, RangeGroup(r, s)) => {
-                    vec![
-                        last,
-                        Combination::Join(vec![Join::RangeGroup(r.clone(), s.clone())]),
-                    ]
+            }
+            Combination::Split {
+                range,
+                filters,
+                prefix,
+                suffix,
+            } => {
+                let values = context.get_by_split_range(range);
+                let values = filters.iter().fold(values, |values, f| f.apply_all(values));
+                if values.is_empty() {
+                    continue;
                 }
+
+                let prefix = expand_joins(context, prefix, quote, strict)?;
+                let suffix = expand_joins(context, suffix, quote, strict)?;
+                args.extend(
+                    values
+                        .into_iter()
+                        .map(|value| format!("{}{}{}", prefix, quote_if(value, quote), suffix)),
+                );
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// Group the args by whether they should be split or joined in the output.
+///
+/// With `each`, the fragments before a split become its prefix, unless they already belong to a
+/// previous split, and the fragments after a split become its suffix. e.g. for "a{1...}b{2...}c"
+/// "a" and "b" are joined to each element of `{1...}`, "c" to each element of `{2...}`.
+fn group_combinations<'a>(
+    fragments: impl Iterator<Item = &'a ArgFragment>,
+    each: bool,
+) -> Vec<Combination> {
+    let fragments = fragments
+        .filter(|fragment| !matches!(fragment, Literal(s) if s.is_empty()))
+        .collect::<Vec<_>>();
+    if let [Conditional(field, present, absent)] = fragments[..] {
+        return vec![Combination::Words {
+            field: (**field).clone(),
+            present: split_words(present),
+            absent: absent.clone(),
+        }];
+    }
+
+    let mut combinations = vec![];
+    for fragment in fragments {
+        let split = match fragment {
+            SplitRangeGroup(range) => Some((range, &[][..])),
+            Filtered(field, filters, _) => match &**field {
+                SplitRangeGroup(range) => Some((range, &filters[..])),
+                _ => None,
             },
+            _ => None,
         };
 
-        acc.append(&mut tail);
+        match split {
+            Some((range, filters)) => {
+                let prefix = match combinations.last() {
+                    Some(Combination::Join(_)) if each => match combinations.pop() {
+                        Some(Combination::Join(joins)) => joins,
+                        _ => unreachable!(),
+                    },
+                    _ => vec![],
+                };
+                combinations.push(Combination::Split {
+                    range: range.clone(),
+                    filters: filters.to_vec(),
+                    prefix,
+                    suffix: vec![],
+                });
+            }
+            None => match combinations.last_mut() {
+                Some(Combination::Join(joins)) => joins.push(fragment.clone()),
+                Some(Combination::Split { suffix, .. }) if each => suffix.push(fragment.clone()),
+                _ => combinations.push(Combination::Join(vec![fragment.clone()])),
+            },
+        }
+    }
+    combinations
+}
 
-        acc
-    })
+/// Split the fragments into words at the whitespace of the literals, the values of the fields
+/// are never split. Quotes group words and are removed, like in a shell.
+fn split_words(fragments: &[ArgFragment]) -> Vec<Vec<ArgFragment>> {
+    let mut words = vec![];
+    let mut word = vec![];
+    let mut quote = None;
+    for fragment in fragments {
+        let literal = match fragment {
+            Literal(literal) => literal,
+            field => {
+                word.push(field.clone());
+                continue;
+            }
+        };
+        let mut text = String::new();
+        for ch in literal.chars() {
+            match (quote, ch) {
+                (None, '\'' | '"') => quote = Some(ch),
+                (Some(open), _) if ch == open => quote = None,
+                (None, _) if ch.is_whitespace() => {
+                    if !text.is_empty() {
+                        word.push(Literal(std::mem::take(&mut text)));
+                    }
+                    if !word.is_empty() {
+                        words.push(std::mem::take(&mut word));
+                    }
+                }
+                _ => text.push(ch),
+            }
+        }
+        if !text.is_empty() {
+            word.push(Literal(text));
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
 }
@@ -0,0 +1,26 @@
+/// Patterns for common formats, selected with `--preset <name>`. The groups are named so that
+/// the templates can refer to them, e.g. `{host}` and `{path}` for access logs.
+pub const PRESETS: &[(&str, &str)] = &[
+    (
+        "iso8601",
+        r"(?P<year>\d{4})-(?P<month>\d{2})-(?P<day>\d{2})(?:[T ](?P<hour>\d{2}):(?P<minute>\d{2})(?::(?P<second>\d{2})(?:\.(?P<fraction>\d+))?)?(?P<timezone>Z|[+-]\d{2}(?::?\d{2})?)?)?",
+    ),
+    (
+        // the combined log format, the default of Apache too
+        "nginx",
+        r#"^(?P<host>\S+) \S+ (?P<user>\S+) \[(?P<time>[^\]]+)\] "(?P<method>[A-Z]+) (?P<path>\S+) (?P<protocol>[^"]+)" (?P<status>\d{3}) (?P<size>\d+|-) "(?P<referer>[^"]*)" "(?P<agent>[^"]*)""#,
+    ),
+    (
+        // RFC 3164, as written to /var/log/syslog
+        "syslog",
+        r"^(?P<time>[A-Z][a-z]{2} [ \d]\d \d{2}:\d{2}:\d{2}) (?P<host>\S+) (?P<program>[^:\[\s]+)(?:\[(?P<pid>\d+)\])?: (?P<message>.*)$",
+    ),
+];
+
+pub fn find(name: &str) -> Result<&'static str, String> {
+    PRESETS
+        .iter()
+        .find(|(preset, _)| *preset == name)
+        .map(|(_, pattern)| *pattern)
+        .ok_or_else(|| format!("unknown preset: {}, see --list-presets", name))
+}
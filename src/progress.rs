@@ -0,0 +1,89 @@
+use std::{
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+const BAR_WIDTH: usize = 30;
+
+/// A progress indicator on stderr, redrawn periodically from its own thread. It shows a bar if
+/// the total number of commands is known, the count and rate of completed commands otherwise.
+pub struct Progress {
+    completed: AtomicUsize,
+    total: OnceLock<usize>,
+    done: AtomicBool,
+    start: Instant,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Progress {
+            completed: AtomicUsize::new(0),
+            total: OnceLock::new(),
+            done: AtomicBool::new(false),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn set_total(&self, total: usize) {
+        let _ = self.total.set(total);
+    }
+
+    pub fn inc(&self) {
+        self.completed.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn start(self: &Arc<Self>) -> JoinHandle<()> {
+        let progress = self.clone();
+        thread::spawn(move || {
+            while !progress.done.load(Ordering::SeqCst) {
+                progress.draw();
+                thread::sleep(REDRAW_INTERVAL);
+            }
+            progress.draw();
+            eprintln!();
+        })
+    }
+
+    /// Stop the redraw thread after drawing the final state
+    pub fn finish(&self, handle: JoinHandle<()>) {
+        self.done.store(true, Ordering::SeqCst);
+        let _ = handle.join();
+    }
+
+    fn draw(&self) {
+        let completed = self.completed.load(Ordering::SeqCst);
+        let line = match self.total.get() {
+            Some(&total) if total > 0 => {
+                let filled = BAR_WIDTH * completed.min(total) / total;
+                format!(
+                    "[{}{}] {:3}% {}/{}",
+                    "#".repeat(filled),
+                    "-".repeat(BAR_WIDTH - filled),
+                    100 * completed.min(total) / total,
+                    completed,
+                    total
+                )
+            }
+            _ => {
+                let elapsed = self.start.elapsed().as_secs_f64();
+                let rate = if elapsed > 0.0 {
+                    completed as f64 / elapsed
+                } else {
+                    0.0
+                };
+                format!("{} done, {:.1}/s", completed, rate)
+            }
+        };
+
+        // clear the rest of the line, in case the previous state was longer
+        let mut stderr = io::stderr().lock();
+        let _ = write!(stderr, "\r{}\x1b[K", line);
+        let _ = stderr.flush();
+    }
+}
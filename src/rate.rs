@@ -0,0 +1,54 @@
+use std::{
+    cmp::max,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Spaces out the commands for `--rate`, shared by all the threads. Up to `burst` commands may
+/// start at once after a pause, then they are held back to `rate` per second on average.
+#[derive(Debug)]
+pub struct RateLimit {
+    next: Mutex<Instant>, // when the next command is due at the steady rate
+    interval: Duration,
+    tolerance: Duration, // how far ahead of the steady rate a burst may run
+}
+
+impl RateLimit {
+    /// Fails if the interval between the commands, or the burst of them, is too long to wait for
+    pub fn new(rate: f64, burst: u32) -> Result<Self, String> {
+        let interval =
+            Duration::try_from_secs_f64(1.0 / rate).map_err(|_| "--rate is too low".to_string())?;
+        let now = Instant::now();
+        let tolerance = interval
+            .checked_mul(burst.saturating_sub(1))
+            .filter(|tolerance| {
+                // the slots are instants, the next one is up to a burst ahead of now
+                tolerance
+                    .checked_add(interval)
+                    .and_then(|ahead| now.checked_add(ahead.checked_mul(2)?))
+                    .is_some()
+            })
+            .ok_or_else(|| "--rate-burst is too large for the --rate".to_string())?;
+        Ok(RateLimit {
+            next: Mutex::new(now),
+            interval,
+            tolerance,
+        })
+    }
+
+    /// Block until the next command may start. The slot is taken before sleeping, so the
+    /// threads waiting at the same time don't wake up together.
+    pub fn acquire(&self) {
+        let wait = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            let slot = max(*next, now);
+            *next = slot + self.interval;
+            slot.duration_since(now).saturating_sub(self.tolerance)
+        };
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
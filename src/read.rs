@@ -0,0 +1,95 @@
+use std::{
+    cmp::min,
+    io::{self, BufRead},
+};
+
+/// Read the next record including its separator, like `read_until` but the separator may be
+/// longer than a byte. With `max_len`, the bytes past it are read but not kept in the buffer.
+pub fn read_record<R: BufRead + ?Sized>(
+    reader: &mut R,
+    separator: &[u8],
+    buffer: &mut Vec<u8>,
+    max_len: Option<usize>,
+) -> io::Result<usize> {
+    if let Some(max_len) = max_len {
+        return read_bounded_record(reader, separator, buffer, max_len);
+    }
+
+    let last = match separator {
+        [byte] => return reader.read_until(*byte, buffer),
+        [.., last] => *last,
+        [] => unreachable!("the record separator can't be empty"),
+    };
+
+    let mut total = 0;
+    loop {
+        let n = reader.read_until(last, buffer)?;
+        total += n;
+        // without the last byte of the separator at the end, the input is exhausted
+        if n == 0 || buffer.ends_with(separator) || buffer.last() != Some(&last) {
+            return Ok(total);
+        }
+    }
+}
+
+fn read_bounded_record<R: BufRead + ?Sized>(
+    reader: &mut R,
+    separator: &[u8],
+    buffer: &mut Vec<u8>,
+    max_len: usize,
+) -> io::Result<usize> {
+    let last = *separator
+        .last()
+        .expect("the record separator can't be empty");
+    let mut total = 0;
+    // the last bytes of the record, once they are no longer kept in the buffer
+    let mut tail: Option<Vec<u8>> = None;
+    loop {
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        };
+        if available.is_empty() {
+            return Ok(total);
+        }
+
+        let (len, found) = match available.iter().position(|&b| b == last) {
+            Some(pos) => (pos + 1, true),
+            None => (available.len(), false),
+        };
+        let chunk = &available[..len];
+        let kept = min(max_len.saturating_sub(buffer.len()), len);
+        buffer.extend_from_slice(&chunk[..kept]);
+        if kept < len || tail.is_some() {
+            let tail = tail.get_or_insert_with(|| {
+                buffer[buffer.len().saturating_sub(separator.len())..].to_vec()
+            });
+            tail.extend_from_slice(&chunk[kept..]);
+            let excess = tail.len().saturating_sub(separator.len());
+            tail.drain(..excess);
+        }
+        reader.consume(len);
+        total += len;
+
+        let end = tail.as_deref().unwrap_or(buffer);
+        if found && end.ends_with(separator) {
+            return Ok(total);
+        }
+    }
+}
+
+/// Remove the separator at the end of the record. Without a custom `--record-separator`, a `\r`
+/// before the newline is removed too, and the `\0` of `-0`.
+pub fn trim_record_end(buffer: &mut Vec<u8>, separator: &[u8], custom: bool) {
+    if custom {
+        if buffer.ends_with(separator) {
+            buffer.truncate(buffer.len() - separator.len());
+        }
+    } else if buffer.ends_with(b"\r\n") {
+        buffer.pop();
+        buffer.pop();
+    } else if buffer.ends_with(b"\n") || buffer.ends_with(b"\0") {
+        buffer.pop();
+    }
+}
@@ -0,0 +1,63 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Condvar, Mutex},
+};
+
+/// The captured output of a command
+#[derive(Default)]
+pub struct Output {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// Buffers the output of commands that complete out of order, releasing it in the order the
+/// commands were started. At most `capacity` commands may be in flight or waiting, so a slow
+/// command can't make the completed results pile up.
+pub struct Reorder {
+    state: Mutex<State>,
+    released: Condvar,
+    capacity: usize,
+}
+
+struct State {
+    next: usize,
+    pending: BTreeMap<usize, Output>,
+}
+
+impl Reorder {
+    pub fn new(capacity: usize) -> Self {
+        Reorder {
+            state: Mutex::new(State {
+                next: 0,
+                pending: BTreeMap::new(),
+            }),
+            released: Condvar::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Block until the command with sequence number `seq` may be started
+    pub fn wait_for_slot(&self, seq: usize) {
+        let mut state = self.state.lock().unwrap();
+        while seq >= state.next + self.capacity {
+            state = self.released.wait(state).unwrap();
+        }
+    }
+
+    /// Record the output of the command `seq`, and release all the output that is now in order.
+    /// Every sequence number has to be completed, even if the command didn't run.
+    pub fn complete(&self, seq: usize, output: Output, mut release: impl FnMut(Output)) {
+        let mut state = self.state.lock().unwrap();
+        state.pending.insert(seq, output);
+
+        loop {
+            let next = state.next;
+            match state.pending.remove(&next) {
+                Some(output) => release(output),
+                None => break,
+            }
+            state.next += 1;
+        }
+        self.released.notify_all();
+    }
+}
@@ -0,0 +1,37 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Bounds the number of commands queued or running in the pool, so that the input is read no
+/// faster than the commands can handle it instead of piling up in memory.
+pub struct Semaphore {
+    permits: Mutex<usize>,
+    released: Condvar,
+}
+
+/// Gives its permit back to the semaphore when dropped, even if the command panicked
+pub struct Permit(Arc<Semaphore>);
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Semaphore {
+            permits: Mutex::new(permits.max(1)),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Block until a permit is free
+    pub fn acquire(self: &Arc<Self>) -> Permit {
+        let mut permits = self.permits.lock().unwrap();
+        while *permits == 0 {
+            permits = self.released.wait(permits).unwrap();
+        }
+        *permits -= 1;
+        Permit(self.clone())
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        *self.0.permits.lock().unwrap() += 1;
+        self.0.released.notify_one();
+    }
+}
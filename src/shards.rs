@@ -0,0 +1,51 @@
+use crate::{create_output, RargsError};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    sync::{Arc, Mutex},
+};
+
+type Shard = Arc<Mutex<BufWriter<File>>>;
+
+/// The files of `--chunk-output`, opened the first time a command writes to them and kept open
+/// until the end. The output of every command is appended in one piece, under the lock of its
+/// shard, so commands writing to different shards don't wait for each other.
+#[derive(Debug)]
+pub struct Shards {
+    files: Mutex<HashMap<String, Shard>>,
+}
+
+impl Shards {
+    pub fn new() -> Self {
+        Shards {
+            files: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The shard at the path, truncated when it's opened the first time in this run
+    pub fn open(&self, path: &str) -> Result<Shard, RargsError> {
+        let mut files = self.files.lock().unwrap();
+        if let Some(shard) = files.get(path) {
+            return Ok(shard.clone());
+        }
+        let shard = Arc::new(Mutex::new(BufWriter::new(create_output(path)?)));
+        files.insert(path.to_string(), shard.clone());
+        Ok(shard)
+    }
+
+    pub fn append(shard: &Shard, output: &[u8]) {
+        let _ = shard.lock().unwrap().write_all(output);
+    }
+
+    pub fn flush(&self) -> Result<(), RargsError> {
+        for (path, shard) in self.files.lock().unwrap().iter() {
+            shard
+                .lock()
+                .unwrap()
+                .flush()
+                .map_err(|error| RargsError::Io(path.clone(), error))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+use serde_json::json;
+use std::{sync::Mutex, time::Duration};
+
+/// Collects the wall-clock durations of the commands for `--stats`
+#[derive(Debug)]
+pub struct Stats {
+    durations: Mutex<Vec<Duration>>,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            durations: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn record(&self, duration: Duration) {
+        self.durations.lock().unwrap().push(duration);
+    }
+
+    /// e.g. "rargs: 3 commands in 1.500s, min 0.100s, mean 0.500s, p95 1.000s, max 1.000s"
+    pub fn summary(&self) -> String {
+        let summary = self.summarize();
+        if summary.count == 0 {
+            return "rargs: 0 commands".to_string();
+        }
+        format!(
+            "rargs: {} commands in {:.3}s, min {:.3}s, mean {:.3}s, p95 {:.3}s, max {:.3}s",
+            summary.count,
+            summary.total.as_secs_f64(),
+            summary.min.as_secs_f64(),
+            summary.mean.as_secs_f64(),
+            summary.p95.as_secs_f64(),
+            summary.max.as_secs_f64(),
+        )
+    }
+
+    /// The same as `summary` as a JSON object, in seconds
+    pub fn summary_json(&self) -> String {
+        let summary = self.summarize();
+        json!({
+            "count": summary.count,
+            "total": summary.total.as_secs_f64(),
+            "min": summary.min.as_secs_f64(),
+            "mean": summary.mean.as_secs_f64(),
+            "p95": summary.p95.as_secs_f64(),
+            "max": summary.max.as_secs_f64(),
+        })
+        .to_string()
+    }
+
+    fn summarize(&self) -> Summary {
+        let mut durations = self.durations.lock().unwrap().clone();
+        durations.sort();
+        let count = durations.len();
+        if count == 0 {
+            return Summary::default();
+        }
+
+        let total = durations.iter().sum::<Duration>();
+        // the nearest rank, so that it's one of the durations
+        let p95 = durations[(count * 95).div_ceil(100) - 1];
+        Summary {
+            count,
+            total,
+            min: durations[0],
+            mean: total.div_f64(count as f64),
+            p95,
+            max: durations[count - 1],
+        }
+    }
+}
+
+#[derive(Default)]
+struct Summary {
+    count: usize,
+    total: Duration,
+    min: Duration,
+    mean: Duration,
+    p95: Duration,
+    max: Duration,
+}
@@ -1,6 +1,4 @@
-use assert_cli;
-
-static RARGS: &'static str = "./target/release/rargs";
+static RARGS: &str = "./target/release/rargs";
 
 #[test]
 fn regex_should_match() {
@@ -251,3 +249,2519 @@ fn test_range_and_split_range() {
         .is("1 2 3 1 2 3")
         .unwrap();
 }
+
+#[test]
+fn test_max_args() {
+    assert_cli::Assert::command(&[RARGS, "--max-args", "2", "echo", "{}"])
+        .stdin("1\n2\n3\n4\n5")
+        .stdout()
+        .is("1 2\n3 4\n5")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--max-args", "0", "echo", "X{1}"])
+        .stdin("1\n2\n3")
+        .stdout()
+        .is("X1 X2 X3")
+        .unwrap();
+}
+
+#[test]
+fn test_prefix() {
+    assert_cli::Assert::command(&[RARGS, "--prefix", "echo", "{}"])
+        .stdin("a\nb")
+        .stdout()
+        .is("[1] a\n[2] b")
+        .unwrap();
+}
+
+#[test]
+fn test_prefix_template() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-d",
+        ",",
+        "--prefix-template",
+        "{1}: ",
+        "sh",
+        "-c",
+        "echo $0; echo $0 >&2",
+        "{2}",
+    ])
+    .stdin("x,1")
+    .stdout()
+    .is("x: 1")
+    .stderr()
+    .is("x: 1")
+    .unwrap();
+}
+
+#[test]
+fn test_dry_run_null_output() {
+    assert_cli::Assert::command(&[RARGS, "-e", "--null-output", "echo", "{}", "x"])
+        .stdin("a b\nc")
+        .stdout()
+        .is("echo\0a b\0x\0\0echo\0c\0x\0\0")
+        .unwrap();
+}
+
+#[test]
+fn test_exit_code_on_failure() {
+    assert_cli::Assert::command(&[RARGS, "sh", "-c", "exit $0", "{}"])
+        .stdin("0\n3\n0")
+        .fails_with(123)
+        .stderr()
+        .is("rargs: 1 command(s) failed")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "rargs-no-such-command"])
+        .stdin("a")
+        .fails_with(127)
+        .unwrap();
+}
+
+#[test]
+fn test_halt_on_error() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--halt-on-error",
+        "sh",
+        "-c",
+        "echo $0; exit $0",
+        "{}",
+    ])
+    .stdin("0\n1\n2")
+    .fails_with(123)
+    .stdout()
+    .is("0\n1")
+    .unwrap();
+}
+
+#[test]
+fn test_timeout() {
+    assert_cli::Assert::command(&[RARGS, "--timeout", "1", "sleep", "{}"])
+        .stdin("0\n10")
+        .fails_with(124)
+        .stderr()
+        .contains("rargs: timeout: sleep 10")
+        .unwrap();
+}
+
+#[test]
+fn test_field_default() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-p",
+        "^(?P<name>[a-z]*),(\\d*)$",
+        "echo",
+        "{name:-anonymous} {2:-0} {3:-none}",
+    ])
+    .stdin("bob,42\n,")
+    .stdout()
+    .is("bob 42 none\nanonymous 0 none")
+    .unwrap();
+}
+
+#[test]
+fn test_quote() {
+    assert_cli::Assert::command(&[RARGS, "-d", ",", "--quote", "sh", "-c", "echo {1} {2}"])
+        .stdin("it's a;b,c")
+        .stdout()
+        .is("it's a;b c")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--quote", "echo", "x{}x"])
+        .stdin("a b")
+        .stdout()
+        .is("x'a b'x")
+        .unwrap();
+}
+
+#[test]
+fn test_filters() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-d",
+        ",",
+        "echo",
+        "{1|upper} {2|lower} {3|trim|upper}",
+        "{4|basename} {4|dirname}",
+    ])
+    .stdin("Ab,Cd,  ef  ,/usr/lib/libc.so")
+    .stdout()
+    .is("AB cd EF libc.so /usr/lib")
+    .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "echo", "{...|upper}", "{..:|}"])
+        .stdin("a b")
+        .stdout()
+        .is("A B a|b")
+        .unwrap();
+}
+
+#[test]
+fn test_unknown_filter() {
+    assert_cli::Assert::command(&[RARGS, "echo", "{1|uper}"])
+        .fails_with(2)
+        .stderr()
+        .is("rargs: unknown filter: uper")
+        .unwrap();
+}
+
+#[test]
+fn test_replace_str() {
+    assert_cli::Assert::command(&[RARGS, "-I", "%{ }%", "echo", "{1} %{2}% %{1..:-}%"])
+        .stdin("a b")
+        .stdout()
+        .is("{1} b a-b")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--replace-str", "[[ ]]", "echo", "[[1|upper]]"])
+        .stdin("a b")
+        .stdout()
+        .is("A")
+        .unwrap();
+}
+
+#[test]
+fn test_invalid_pattern() {
+    assert_cli::Assert::command(&[RARGS, "-p", "(a", "echo", "{1}"])
+        .fails_with(2)
+        .stderr()
+        .contains("rargs: invalid pattern:")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "-d", "[", "echo", "{1}"])
+        .fails_with(2)
+        .stderr()
+        .contains("rargs: invalid pattern:")
+        .unwrap();
+}
+
+#[test]
+fn test_invalid_utf8_line() {
+    assert_cli::Assert::command(&[RARGS, "echo", "{LN} {}"])
+        .stdin(&b"a\n\xffb\nc"[..])
+        .stdout()
+        .is("1 a\n3 c")
+        .stderr()
+        .is("rargs: skip line 2: invalid UTF-8")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--lossy", "echo", "{}"])
+        .stdin(&b"a\n\xffb\nc"[..])
+        .stdout()
+        .is("a\n\u{FFFD}b\nc")
+        .unwrap();
+}
+
+#[test]
+fn test_exit_on_missing_field() {
+    assert_cli::Assert::command(&[RARGS, "--exit-on-missing-field", "echo", "{3}"])
+        .stdin("a b c\na b")
+        .fails_with(1)
+        .stdout()
+        .is("c")
+        .stderr()
+        .contains("rargs: line 2: missing field {3} in \"a b\"")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--exit-on-missing-field", "echo", "{3:-x}"])
+        .stdin("a b")
+        .stdout()
+        .is("x")
+        .unwrap();
+}
+
+#[test]
+fn test_command_file() {
+    let path = std::env::temp_dir().join("rargs-test-command-file");
+    std::fs::write(
+        &path,
+        "# print the fields\necho\n\n{2}\n  # comment\nx {1}\n",
+    )
+    .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--command-file", path.to_str().unwrap()])
+        .stdin("a b")
+        .stdout()
+        .is("b x a")
+        .unwrap();
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_nth_filter() {
+    assert_cli::Assert::command(&[RARGS, "echo", "{...|nth:2}", "{2...|nth:-1}", "X{1|nth:1}"])
+        .stdin("a b c d")
+        .stdout()
+        .is("b d Xa")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "sh", "-c", "echo $#: $@", "sh", "{...|nth:2..3}"])
+        .stdin("a b c d")
+        .stdout()
+        .is("2: b c")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "echo", "X", "{...|nth:9}", "{1|nth:2}X"])
+        .stdin("a b c d")
+        .stdout()
+        .is("X X")
+        .unwrap();
+}
+
+#[test]
+fn test_each() {
+    assert_cli::Assert::command(&[RARGS, "--each", "echo", "prefix-{...}.txt"])
+        .stdin("a b c")
+        .stdout()
+        .is("prefix-a.txt prefix-b.txt prefix-c.txt")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--each", "echo", "<{1...1}|{2...}>"])
+        .stdin("a b c")
+        .stdout()
+        .is("<a| b> c>")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "echo", "prefix-{...}"])
+        .stdin("a b c")
+        .stdout()
+        .is("prefix- a b c")
+        .unwrap();
+}
+
+#[test]
+fn test_input_file() {
+    let first = std::env::temp_dir().join("rargs-test-input-file-1");
+    let second = std::env::temp_dir().join("rargs-test-input-file-2");
+    std::fs::write(&first, "a\nb\n").unwrap();
+    std::fs::write(&second, "c\n").unwrap();
+    let (first_path, second_path) = (first.to_str().unwrap(), second.to_str().unwrap());
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-a",
+        first_path,
+        "--input-file",
+        second_path,
+        "echo",
+        "{LN} {0}",
+    ])
+    .stdout()
+    .is("1 a\n2 b\n3 c")
+    .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--reset-linenum",
+        "-a",
+        first_path,
+        "-a",
+        second_path,
+        "echo",
+        "{LN} {0}",
+    ])
+    .stdout()
+    .is("1 a\n2 b\n1 c")
+    .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-a",
+        "/nonexistent/rargs-input",
+        "-a",
+        second_path,
+        "echo",
+        "{0}",
+    ])
+    .fails_with(1)
+    .stdout()
+    .is("c")
+    .stderr()
+    .contains("/nonexistent/rargs-input")
+    .unwrap();
+
+    std::fs::remove_file(&first).unwrap();
+    std::fs::remove_file(&second).unwrap();
+}
+
+#[test]
+fn test_json() {
+    assert_cli::Assert::command(&[RARGS, "--json", "echo", "{user}", "{address.city}", "{tags.1}", "{id}", "{none:-x}"])
+        .stdin(r#"{"user": "alice", "id": 1, "address": {"city": "Paris"}, "tags": ["a", "b"], "none": null}"#)
+        .stdout()
+        .is("alice Paris b 1 x")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--json", "echo", "{2}", "{...}"])
+        .stdin("[\"a\", 2, true]\nnot json\n")
+        .stdout()
+        .is("2 a 2 true")
+        .stderr()
+        .contains("skip line 2: invalid JSON")
+        .unwrap();
+}
+
+#[test]
+fn test_csv() {
+    assert_cli::Assert::command(&[RARGS, "--csv", "echo", "{3}|{2}|{1}"])
+        .stdin(r#"a,"b,c","say ""hi""""#)
+        .stdout()
+        .is(r#"say "hi"|b,c|a"#)
+        .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--csv",
+        "--csv-delimiter",
+        ";",
+        "echo",
+        "{2}",
+        "{-1}",
+    ])
+    .stdin("a;;\"c;d\"")
+    .stdout()
+    .is(" c;d")
+    .unwrap();
+}
+
+#[test]
+fn test_header() {
+    assert_cli::Assert::command(&[
+        RARGS, "--csv", "--header", "echo", "{LN}", "{email}", "{name}", "{age:-?}",
+    ])
+    .stdin("name,email,age\nalice,alice@example.com,30\nbob,bob@example.com\n")
+    .stdout()
+    .is("2 alice@example.com alice 30\n3 bob@example.com bob ?")
+    .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "-d", " ", "--header", "echo", "{b}"])
+        .stdin("a b\n1 2\n")
+        .stdout()
+        .is("2")
+        .unwrap();
+}
+
+#[test]
+fn test_retries() {
+    let path = std::env::temp_dir().join("rargs-test-retries");
+    let _ = std::fs::remove_file(&path);
+    let path = path.to_str().unwrap();
+
+    // fails on the first two attempts
+    let script = "echo x >> {0}; test $(wc -l < {0}) -ge 3";
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--retries",
+        "2",
+        "--retry-delay",
+        "0",
+        "sh",
+        "-c",
+        script,
+    ])
+    .stdin(path)
+    .stderr()
+    .contains("retry 2/2")
+    .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--retries",
+        "2",
+        "--retry-delay",
+        "0",
+        "--retry-on-codes",
+        "7,8",
+        "sh",
+        "-c",
+        "exit 1",
+    ])
+    .stdin("a")
+    .fails_with(123)
+    .stderr()
+    .doesnt_contain("retry")
+    .unwrap();
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_keep_order() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-P",
+        "3",
+        "--keep-order",
+        "sh",
+        "-c",
+        "sleep 0.{0}; echo {0}",
+    ])
+    .stdin("3\n1\n2\n0")
+    .stdout()
+    .is("3\n1\n2\n0")
+    .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-P",
+        "2",
+        "--keep-order",
+        "--prefix",
+        "sh",
+        "-c",
+        "sleep 0.{0}; echo {0}",
+    ])
+    .stdin("2\n0")
+    .stdout()
+    .is("[1] 2\n[2] 0")
+    .unwrap();
+}
+
+#[test]
+fn test_slice_filter() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "echo",
+        "{1|slice:0:8}",
+        "{2|slice:-3:}",
+        "{2|slice::-1}",
+        "{2|slice:2:99}",
+        "[{2|slice:5:2}]",
+        "{2|slice:1:4294967296}",
+        "{2|slice:-4294967296:2}",
+    ])
+    .stdin("0123456789abcdef héllo")
+    .stdout()
+    .is("01234567 llo héll llo [] éllo hé")
+    .unwrap();
+}
+
+#[test]
+fn test_env_var() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "echo",
+        "{$RARGS_TEST_DIR}/{1}",
+        "{$RARGS_TEST_UNSET:-x}",
+        "[{$RARGS_TEST_UNSET}]",
+    ])
+    .with_env(assert_cli::Environment::inherit().insert("RARGS_TEST_DIR", "/tmp"))
+    .stdin("a")
+    .stdout()
+    .is("/tmp/a x []")
+    .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--exit-on-missing-field",
+        "echo",
+        "{$RARGS_TEST_UNSET}",
+    ])
+    .stdin("a")
+    .fails_with(1)
+    .stderr()
+    .contains("missing field {$RARGS_TEST_UNSET}")
+    .unwrap();
+}
+
+#[test]
+fn test_no_run_if_empty() {
+    assert_cli::Assert::command(&[RARGS, "-r", "-p", "(\\d*)", "echo", "{1}"])
+        .stdin("1\n\nabc\n2")
+        .stdout()
+        .is("1\n2")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "-r", "echo", "x{1}"])
+        .stdin("\n")
+        .stdout()
+        .is("x")
+        .unwrap();
+}
+
+#[test]
+fn test_line() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-p",
+        "(?P<LINE>\\w+) (\\w+)",
+        "echo",
+        "{LINE}",
+        "{L}",
+        "{2}",
+    ])
+    .stdin("a b")
+    .stdout()
+    .is("a b a b b")
+    .unwrap();
+}
+
+#[test]
+fn test_count_first() {
+    assert_cli::Assert::command(&[RARGS, "--count-first", "echo", "{LN}/{LINECOUNT}", "{NR}"])
+        .stdin("a\nb\nc")
+        .stdout()
+        .is("1/3 3\n2/3 3\n3/3 3")
+        .unwrap();
+
+    // the header is numbered and counted, the lines filtered out are neither
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--header",
+        "--count-first",
+        "--no-match",
+        "skip",
+        "echo",
+        "{LN}/{NR}",
+    ])
+    .stdin("h\na\nskip\nb")
+    .stdout()
+    .is("2/3\n3/3")
+    .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "echo", "[{LINECOUNT}]"])
+        .stdin("a")
+        .stdout()
+        .is("[]")
+        .unwrap();
+}
+
+#[test]
+fn test_stdin_source() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--stdin-passthrough",
+        "sh",
+        "-c",
+        "tr a-z A-Z; echo {LN}",
+    ])
+    .stdin("abc\ndef")
+    .stdout()
+    .is("ABC\n1\nDEF\n2")
+    .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--stdin-passthrough",
+        "--max-args",
+        "2",
+        "sh",
+        "-c",
+        "wc -l",
+    ])
+    .stdin("a\nb")
+    .stdout()
+    .is("2")
+    .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "sh", "-c", "cat; echo {}"])
+        .stdin("a")
+        .stdout()
+        .is("a")
+        .unwrap();
+}
+
+#[test]
+fn test_progress_not_on_terminal() {
+    assert_cli::Assert::command(&[RARGS, "--progress", "--count-first", "echo", "{}"])
+        .stdin("a\nb")
+        .stdout()
+        .is("a\nb")
+        .stderr()
+        .is("")
+        .unwrap();
+}
+
+#[test]
+fn test_shell() {
+    assert_cli::Assert::command(&[RARGS, "--shell", "echo {1} | tr a-z A-Z;", "echo", "{2}"])
+        .stdin("ab cd")
+        .stdout()
+        .is("AB\ncd")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--shell-path", "bash", "--quote", "echo $0 {}"])
+        .stdin("a;b")
+        .stdout()
+        .is("bash a;b")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "-e", "--shell", "echo {1} >", "{2}.txt"])
+        .stdin("a b")
+        .stdout()
+        .is("sh -c echo a > b.txt")
+        .unwrap();
+}
+
+#[test]
+fn test_dry_run_quoted_output() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-e",
+        "--quoted-output",
+        "-d",
+        ",",
+        "echo",
+        "{1}",
+        "x-{2}",
+    ])
+    .stdin("a b,it's\nc,d")
+    .stdout()
+    .is("echo 'a b' 'x-it'\\''s' # line 1\necho c x-d # line 2")
+    .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-e",
+        "--quoted-output",
+        "--max-args",
+        "2",
+        "echo",
+        "{}",
+    ])
+    .stdin("a\nb")
+    .stdout()
+    .is("echo a b # lines 1-2")
+    .unwrap();
+}
+
+#[test]
+fn test_sub_filter() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "echo",
+        "{1|sub:/o/0/}",
+        "{1|sub:/o/0/g}",
+        "{2|sub:#(\\w+)-(\\w+)#$2-$1#|upper}",
+    ])
+    .stdin("foo ab-cd")
+    .stdout()
+    .is("f0o f00 CD-AB")
+    .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "echo", "{1|sub:/(/x/}"])
+        .fails_with(2)
+        .stderr()
+        .contains("invalid regex in filter sub:/(/x/")
+        .unwrap();
+}
+
+#[test]
+fn test_separator_escapes() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "echo",
+        "{1..2:\\t}",
+        "{2..3:\\n}",
+        "{..:\\\\}",
+        "{..:\\x}",
+    ])
+    .stdin("a b c")
+    .stdout()
+    .is("a\tb b\nc a\\b\\c a\\xb\\xc")
+    .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "-s", "\\t", "echo", "{..}"])
+        .stdin("a b")
+        .stdout()
+        .is("a\tb")
+        .unwrap();
+}
+
+#[test]
+fn test_max_procs() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-P",
+        "4",
+        "--keep-order",
+        "sh",
+        "-c",
+        "sleep 0.3; echo {}",
+    ])
+    .stdin("1\n2\n3\n4")
+    .stdout()
+    .is("1\n2\n3\n4")
+    .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "-w", "2", "echo", "{}"])
+        .stdin("a")
+        .stdout()
+        .is("a")
+        .stderr()
+        .contains("--worker is deprecated")
+        .unwrap();
+}
+
+#[test]
+fn test_multiple_patterns() {
+    let input = "user=alice\nid:42\nunknown line";
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-p",
+        "^user=(?P<v>.*)",
+        "-p",
+        "^id:(?P<v>.*)",
+        "echo",
+        "{LN} {v}",
+    ])
+    .stdin(input)
+    .stdout()
+    .is("1 alice\n2 42")
+    .stderr()
+    .contains("skip line 3: no pattern matches")
+    .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-p",
+        "^user=(.*)",
+        "-p",
+        "^id:(.*)",
+        "--fallback-split",
+        "echo",
+        "{1}",
+    ])
+    .stdin(input)
+    .stdout()
+    .is("alice\n42\nunknown")
+    .unwrap();
+}
+
+#[test]
+fn test_single_match() {
+    assert_cli::Assert::command(&[RARGS, "-p", "(\\w)(\\d)", "echo", "{1}{2}", "{3}{4}"])
+        .stdin("a1 b2")
+        .stdout()
+        .is("a1 b2")
+        .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-p",
+        "(\\w)(\\d)",
+        "--single-match",
+        "echo",
+        "{1}{2}",
+        "[{3}{4}]",
+    ])
+    .stdin("a1 b2")
+    .stdout()
+    .is("a1 []")
+    .unwrap();
+}
+
+#[test]
+fn test_conditional() {
+    let args = &[
+        RARGS,
+        "-p",
+        "(?P<name>\\w+)(?: (?P<user>\\w+))?",
+        "sh",
+        "-c",
+        "echo $#: $@",
+        "sh",
+        "{name}",
+        "{user?--user={user|upper}}",
+        "{user?has:no} user",
+    ];
+    assert_cli::Assert::command(args)
+        .stdin("a b\nc")
+        .stdout()
+        .is("3: a --user=B has user\n2: c no user")
+        .unwrap();
+
+    // a whole argument is split into the words of its branch, not the values of its fields
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-p",
+        "(?P<name>\\w+)(?: (?P<user>.+))?",
+        "printf",
+        "[%s]\\n",
+        "{user?--user {user} 'a b':no user}",
+    ])
+    .stdin("a alice smith\nb")
+    .stdout()
+    .is("[--user]\n[alice smith]\n[a b]\n[no user]")
+    .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "echo", "{1?{...}}"])
+        .fails_with(2)
+        .stderr()
+        .contains("not allowed in a conditional")
+        .unwrap();
+
+    // braces that are not fields are still kept as they are
+    assert_cli::Assert::command(&[RARGS, "echo", "{ print {1} }"])
+        .stdin("a")
+        .stdout()
+        .is("{ print a }")
+        .unwrap();
+}
+
+#[test]
+fn test_fixed_delimiter() {
+    assert_cli::Assert::command(&[RARGS, "-F", ".", "echo", "{2}", "{-1}"])
+        .stdin("a.b|c.d")
+        .stdout()
+        .is("b|c d")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--fixed-delimiter", "||", "echo", "{...}"])
+        .stdin("a||b|c||d")
+        .stdout()
+        .is("a b|c d")
+        .unwrap();
+}
+
+#[test]
+fn test_trim() {
+    assert_cli::Assert::command(&[
+        RARGS, "-d", ",", "--trim", "echo", "[{1}]", "[{2}]", "[{0}]",
+    ])
+    .stdin(" a , b ")
+    .stdout()
+    .is("[a] [b] [ a , b ]")
+    .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "-d", ",", "--trim-chars", "\"' ", "echo", "{1}-{2}"])
+        .stdin("'a', \"b\"")
+        .stdout()
+        .is("a-b")
+        .unwrap();
+}
+
+#[test]
+fn test_step() {
+    assert_cli::Assert::command(&[RARGS, "-n", "10", "--step", "10", "echo", "{LN}"])
+        .stdin("a\nb\nc")
+        .stdout()
+        .is("10\n20\n30")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "-n", "1", "--step", "-1", "echo", "{LN}"])
+        .stdin("a\nb\nc")
+        .stdout()
+        .is("1\n0\n-1")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "-n", "2147483646", "echo", "{LN}"])
+        .stdin("a\nb\nc")
+        .fails_with(1)
+        .stdout()
+        .is("2147483646\n2147483647")
+        .stderr()
+        .contains("line number out of range")
+        .unwrap();
+}
+
+#[test]
+fn test_pad_filter() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-n",
+        "7",
+        "echo",
+        "file-{LINENUM|pad:4}.txt",
+        "{1|pad:4}",
+        "{2|pad:4}",
+        "{3|pad:2}",
+    ])
+    .stdin("-7 ab 12345")
+    .stdout()
+    .is("file-0007.txt -007 ab 12345")
+    .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "echo", "{1|pad:18446744073709551615}"])
+        .fails_with(2)
+        .stderr()
+        .contains("invalid filter argument: pad:18446744073709551615, at most 1024")
+        .unwrap();
+}
+
+#[test]
+fn test_group_by() {
+    assert_cli::Assert::command(&[RARGS, "--group-by", "{1}", "echo", "{1}:", "{2..}"])
+        .stdin("web 1 a\nweb 2 b\ndb 3 c\nweb 4 d")
+        .stdout()
+        .is("web: 1 a web: 2 b\ndb: 3 c\nweb: 4 d")
+        .unwrap();
+}
+
+#[test]
+fn test_skip() {
+    assert_cli::Assert::command(&[RARGS, "--skip", "2", "-n", "5", "echo", "{LN}", "{}"])
+        .stdin("# a\n# b\nx\ny")
+        .stdout()
+        .is("5 x\n6 y")
+        .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--skip",
+        "1",
+        "--number-skipped",
+        "echo",
+        "{LN}",
+        "{}",
+    ])
+    .stdin("# a\nx\ny")
+    .stdout()
+    .is("2 x\n3 y")
+    .unwrap();
+}
+
+#[test]
+fn test_limit() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--skip",
+        "1",
+        "--limit",
+        "2",
+        "-j",
+        "2",
+        "--keep-order",
+        "echo",
+        "{LN}",
+        "{}",
+    ])
+    .stdin("a\nb\nc\nd")
+    .stdout()
+    .is("1 b\n2 c")
+    .unwrap();
+}
+
+#[test]
+fn test_output_to() {
+    let dir = std::env::temp_dir().join("rargs-test-output-to");
+    let _ = std::fs::remove_dir_all(&dir);
+    let template = format!("{}/{{1}}/out.log", dir.display());
+
+    assert_cli::Assert::command(&[RARGS, "--output-to", &template, "echo", "{2}"])
+        .stdin("a 1\nb 2")
+        .stdout()
+        .is("")
+        .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(dir.join("a").join("out.log")).unwrap(),
+        "1\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.join("b").join("out.log")).unwrap(),
+        "2\n"
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_append_args() {
+    assert_cli::Assert::command(&[RARGS, "--append-args", "printf", "[%s]", "{1}"])
+        .stdin("a b  c")
+        .stdout()
+        .is("[a][a b  c]")
+        .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--append-args",
+        "--split-appended",
+        "printf",
+        "[%s]",
+        "{1}",
+    ])
+    .stdin("a b  c")
+    .stdout()
+    .is("[a][a][b][c]")
+    .unwrap();
+}
+
+#[test]
+fn test_on_filter() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "printf",
+        "[%s]",
+        "{1...1|on:,}",
+        "{1...1|on:,|nonempty|upper}",
+        "{1|on:,}",
+    ])
+    .stdin("a,b,,c x")
+    .stdout()
+    .is("[a][b][][c][A][B][C][a b  c]")
+    .unwrap();
+}
+
+#[test]
+fn test_group_count() {
+    assert_cli::Assert::command(&[RARGS, "-d", ",", "echo", "{#}", "{#|pad:2}"])
+        .stdin("a,b,c\n\nd")
+        .stdout()
+        .is("3 03\n1 01\n1 01")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "-p", r"(\d+)-(\d+)", "echo", "{#}"])
+        .stdin("1-2\nx")
+        .stdout()
+        .is("2\n0")
+        .unwrap();
+}
+
+#[test]
+fn test_record_separator() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--record-separator",
+        "::",
+        "--count-first",
+        "echo",
+        "{LN}/{NR}",
+        "{}",
+    ])
+    .stdin("a:b::c::::d:")
+    .stdout()
+    .is("1/4 a:b\n2/4 c\n3/4 \n4/4 d:")
+    .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--record-separator", "", "echo"])
+        .fails_with(2)
+        .stderr()
+        .contains("the record separator can't be empty")
+        .unwrap();
+}
+
+#[test]
+fn test_prompt_runs_one_command_at_a_time() {
+    assert_cli::Assert::command(&[RARGS, "--prompt", "-P", "2", "echo"])
+        .fails_with(1)
+        .stderr()
+        .contains("cannot be used with")
+        .unwrap();
+}
+
+#[test]
+fn test_group_offsets() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-p",
+        r"(?P<key>\w+)=(\w+)",
+        "echo",
+        "{@key}",
+        "{@1}",
+        "{@2}",
+        "{@4}",
+        "{@0}",
+        "{@5:-none}",
+    ])
+    .stdin("é=1  ab=cd")
+    .stdout()
+    .is("6 0 3 9 0 none")
+    .unwrap();
+}
+
+#[test]
+fn test_dry_run_color() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-p",
+        r"(\w)=(\d)",
+        "--color",
+        "always",
+        "-e",
+        "echo",
+        "{2}",
+    ])
+    .stdin("a=1 b")
+    .stdout()
+    .is("# \x1b[31ma\x1b[0m=\x1b[32m1\x1b[0m b\necho 1")
+    .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "-p", r"(\w)=(\d)", "-e", "echo", "{2}"])
+        .stdin("a=1 b")
+        .stdout()
+        .is("echo 1")
+        .unwrap();
+}
+
+#[test]
+fn test_with_fields() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-p",
+        r"(?P<code>\d{3})",
+        "--with-fields",
+        "echo",
+        "{code}",
+        "{1}",
+        "{-1}",
+        "{#}",
+    ])
+    .stdin("GET /index.html 200 12ms")
+    .stdout()
+    .is("200 GET 12ms 4")
+    .unwrap();
+}
+
+#[test]
+fn test_export_env() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-p",
+        r"(?P<name>\w+) (\d+)",
+        "--env",
+        "sh",
+        "-c",
+        "echo $RARGS_name $RARGS_2 $RARGS_LINENUM",
+    ])
+    .stdin("alice 42")
+    .stdout()
+    .is("alice 42 1")
+    .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--json",
+        "--env-prefix",
+        "F_",
+        "sh",
+        "-c",
+        "echo $F_user $F_LN",
+    ])
+    .stdin(r#"{"user": "bob", "n": null}"#)
+    .stdout()
+    .is("bob 1")
+    .unwrap();
+}
+
+#[test]
+fn test_chdir() {
+    let dir = std::env::temp_dir().join("rargs-test-chdir");
+    std::fs::create_dir_all(dir.join("project")).unwrap();
+    let input = format!("{}/project\n{}/missing", dir.display(), dir.display());
+
+    assert_cli::Assert::command(&[RARGS, "--chdir", "{}", "sh", "-c", "basename $PWD"])
+        .stdin(input)
+        .fails_with(127)
+        .stdout()
+        .is("project")
+        .stderr()
+        .contains("missing: no such directory")
+        .unwrap();
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_interrupt_waits_for_running_commands() {
+    let mut child = std::process::Command::new(RARGS)
+        .args(["sh", "-c", "sleep 0.5; echo done {}"])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    // keep stdin open so that only the interrupt stops the input
+    let mut stdin = child.stdin.take().unwrap();
+    std::io::Write::write_all(&mut stdin, b"1\n2\n3\n").unwrap();
+
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    let status = std::process::Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(130));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "done 1\n");
+    drop(stdin);
+}
+
+#[test]
+fn test_interrupt_flushes_chunk_output() {
+    let dir = std::env::temp_dir().join("rargs-test-interrupt-chunk-output");
+    let mut child = std::process::Command::new(RARGS)
+        .args(["-j", "2", "--chunk-output"])
+        .arg(dir.join("out.txt"))
+        .args(["echo", "done {}"])
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    // keep stdin open so that only the interrupt stops the input
+    let mut stdin = child.stdin.take().unwrap();
+    std::io::Write::write_all(&mut stdin, b"1\n2\n").unwrap();
+
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    let status = std::process::Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    assert_eq!(child.wait().unwrap().code(), Some(130));
+    let mut lines: Vec<String> = std::fs::read_to_string(dir.join("out.txt"))
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect();
+    std::fs::remove_dir_all(&dir).unwrap();
+    lines.sort();
+    assert_eq!(lines, ["done 1", "done 2"]);
+    drop(stdin);
+}
+
+#[test]
+fn test_queue_size() {
+    // with room for a single command, the commands can't overtake each other
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-P",
+        "4",
+        "--queue-size",
+        "1",
+        "sh",
+        "-c",
+        "sleep 0.$((4 - {})); echo {}",
+    ])
+    .stdin("1\n2\n3")
+    .stdout()
+    .is("1\n2\n3")
+    .unwrap();
+}
+
+#[test]
+fn test_url_filters() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-d",
+        r"\t",
+        "echo",
+        "{1|urlencode}",
+        "{1|urlencode:path}",
+        "{1|urlencode|urldecode}",
+        "{2|urldecode}",
+    ])
+    .stdin("a b/c?d=é~\t%41%2+%+1%")
+    .stdout()
+    .is("a%20b%2Fc%3Fd%3D%C3%A9~ a%20b/c%3Fd=%C3%A9~ a b/c?d=é~ A%2+%+1%")
+    .unwrap();
+}
+
+#[test]
+fn test_json_filter() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-d",
+        "\n",
+        "echo",
+        r#"{"text": "{1|json}"}"#,
+        "{1|json:quoted}",
+    ])
+    .stdin("say \"hi\"\t\\o/")
+    .stdout()
+    .is(r#"{"text": "say \"hi\"\t\\o/"} "say \"hi\"\t\\o/""#)
+    .unwrap();
+}
+
+#[test]
+fn test_line_buffered() {
+    // the command only prints the second line once the first one is read
+    let ready = std::env::temp_dir().join("rargs-test-line-buffered");
+    let _ = std::fs::remove_file(&ready);
+    let mut child = std::process::Command::new(RARGS)
+        .args([
+            "--prefix",
+            "--line-buffered",
+            "sh",
+            "-c",
+            "echo a; for i in $(seq 600); do [ -e $0 ] && break; sleep 0.1; done; [ -e $0 ] && echo b || echo late",
+        ])
+        .arg(&ready)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    std::io::Write::write_all(&mut child.stdin.take().unwrap(), b"x\n").unwrap();
+
+    // the first line is forwarded while the command is still running
+    let mut stdout = std::io::BufReader::new(child.stdout.take().unwrap());
+    let mut line = String::new();
+    std::io::BufRead::read_line(&mut stdout, &mut line).unwrap();
+    assert_eq!(line, "[1] a\n");
+    std::fs::write(&ready, "").unwrap();
+
+    line.clear();
+    std::io::BufRead::read_line(&mut stdout, &mut line).unwrap();
+    assert_eq!(line, "[1] b\n");
+    assert!(child.wait().unwrap().success());
+    std::fs::remove_file(&ready).unwrap();
+}
+
+#[test]
+fn test_prev_fields() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-p",
+        r"(?P<key>\w+) (\d+)",
+        "-P",
+        "2",
+        "--keep-order",
+        "echo",
+        "{prev:key:-none}->{key}",
+        "{prev:2:-0}",
+    ])
+    .stdin("a 1\nb 5\nc 2")
+    .stdout()
+    .is("none->a 0\na->b 1\nb->c 5")
+    .unwrap();
+}
+
+#[test]
+fn test_repeat() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--repeat",
+        "2",
+        "-P",
+        "3",
+        "--keep-order",
+        "echo",
+        "{}-{REPEAT}",
+    ])
+    .stdin("a\nb")
+    .stdout()
+    .is("a-1\na-2\nb-1\nb-2")
+    .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--repeat", "0", "echo"])
+        .fails_with(2)
+        .stderr()
+        .contains("--repeat must be at least 1")
+        .unwrap();
+}
+
+#[test]
+fn test_arithmetic_filters() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-n",
+        "9",
+        "echo",
+        "{LN|add:100}",
+        "{LN|sub:10}",
+        "{LN|mul:-2}",
+        "{LN|mod:4}",
+        "{LN|sub:10|mod:4}",
+        "{1|add:1}",
+        "{LN|add:9223372036854775807}",
+    ])
+    .stdin("x")
+    .stdout()
+    .is("109 -1 -18 1 3 x 9")
+    .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "echo", "{LN|mod:0}"])
+        .fails_with(2)
+        .stderr()
+        .contains("invalid filter argument: mod:0")
+        .unwrap();
+}
+
+#[test]
+fn test_hash_filters() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "echo",
+        "{1|md5}",
+        "{1|sha1}",
+        "{1|sha256}",
+        "{1|sha256:8}",
+    ])
+    .stdin("hello")
+    .stdout()
+    .is("5d41402abc4b2a76b9719d911017c592 aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d 2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824 2cf24dba")
+    .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "echo", "{1|sha256:0}"])
+        .fails_with(2)
+        .stderr()
+        .contains("invalid filter argument: sha256:0")
+        .unwrap();
+}
+
+#[test]
+fn test_bucket_filter() {
+    assert_cli::Assert::command(&[RARGS, "echo", "{1}:{1|bucket:16}:{1|bucket:1}"])
+        .stdin("a\nb\nhello")
+        .stdout()
+        .is("a:10:0\nb:10:0\nhello:14:0")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "echo", "{1|bucket:0}"])
+        .fails_with(2)
+        .stderr()
+        .contains("invalid filter argument: bucket:0")
+        .unwrap();
+}
+
+#[test]
+fn test_on_empty() {
+    assert_cli::Assert::command(&[RARGS, "echo", "{LN}:{}"])
+        .stdin("a\n\nb")
+        .stdout()
+        .is("1:a\n2:\n3:b")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--on-empty", "skip", "echo", "{LN}:{}"])
+        .stdin("a\n\nb")
+        .stdout()
+        .is("1:a\n3:b")
+        .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--max-args",
+        "0",
+        "--on-empty",
+        "separator",
+        "echo",
+        "{}",
+    ])
+    .stdin("a\nb\n\n\nc")
+    .stdout()
+    .is("a b\nc")
+    .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--group-by",
+        "{1}",
+        "--on-empty",
+        "separator",
+        "echo",
+        "{2}",
+    ])
+    .stdin("x 1\n\nx 2\ny 3")
+    .stdout()
+    .is("1\n2\n3")
+    .unwrap();
+}
+
+#[test]
+fn test_presets() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--preset",
+        "nginx",
+        "echo",
+        "{host} {method} {path} {status} {size} {agent}",
+    ])
+    .stdin(r#"127.0.0.1 - - [10/Oct/2026:13:55:36 +0000] "GET /index.html HTTP/1.1" 200 612 "-" "curl/8.0""#)
+    .stdout()
+    .is("127.0.0.1 GET /index.html 200 612 curl/8.0")
+    .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--preset",
+        "syslog",
+        "--preset",
+        "iso8601",
+        "echo",
+        "{program}{pid} {year}{hour}",
+    ])
+    .stdin("Oct 14 09:01:02 host sshd[42]: Accepted\n2026-10-14T09:01:02Z")
+    .stdout()
+    .is("sshd42 \n 202609")
+    .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--list-presets"])
+        .stdout()
+        .contains("nginx\t^(?P<host>")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--preset", "apache", "echo"])
+        .fails_with(2)
+        .stderr()
+        .contains("unknown preset: apache")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--single-match", "echo"])
+        .fails_with(2)
+        .stderr()
+        .contains("--single-match requires --pattern or --preset")
+        .unwrap();
+}
+
+#[test]
+fn test_verbose() {
+    assert_cli::Assert::command(&[RARGS, "-t", "--max-args", "2", "echo", "{}"])
+        .stdin("a b\nc\nd")
+        .stdout()
+        .is("a b c\nd")
+        .stderr()
+        .is("+ echo 'a b' c # lines 1-2\n+ echo d # line 3")
+        .unwrap();
+}
+
+#[test]
+fn test_stats() {
+    assert_cli::Assert::command(&[RARGS, "--stats", "true"])
+        .stdin("a\nb\nc")
+        .stderr()
+        .contains("rargs: 3 commands in ")
+        .stderr()
+        .contains(", p95 ")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--stats-json", "true"])
+        .stdin("a\nb")
+        .stderr()
+        .contains(r#""count":2,"#)
+        .unwrap();
+
+    // the second command waits a second for the rate limit, which isn't counted
+    assert_cli::Assert::command(&[RARGS, "--rate", "1", "--stats-json", "true"])
+        .stdin("a\nb")
+        .stderr()
+        .contains(r#""max":0."#)
+        .unwrap();
+
+    // nor is the delay of a second before the retry
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--retries",
+        "1",
+        "--retry-delay",
+        "1",
+        "--stats-json",
+        "false",
+    ])
+    .stdin("a")
+    .fails_with(123)
+    .stderr()
+    .contains(r#""max":0."#)
+    .unwrap();
+}
+
+#[test]
+fn test_prompt_conflicts_with_dry_run() {
+    assert_cli::Assert::command(&[RARGS, "--prompt", "-e", "echo"])
+        .fails_with(1)
+        .stderr()
+        .contains("cannot be used with")
+        .unwrap();
+}
+
+#[test]
+fn test_tee() {
+    assert_cli::Assert::command(&[RARGS, "--tee", "echo", "{}"])
+        .stdin("a b\nc")
+        .stdout()
+        .is("echo 'a b' # line 1\na b\necho c # line 2\nc")
+        .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--tee",
+        "--keep-order",
+        "-P",
+        "2",
+        "sh",
+        "-c",
+        "sleep $0; echo {}",
+        "{}",
+    ])
+    .stdin("0.2\n0")
+    .stdout()
+    .is("sh -c 'sleep $0; echo 0.2' 0.2 # line 1\n0.2\nsh -c 'sleep $0; echo 0' 0 # line 2\n0")
+    .unwrap();
+}
+
+#[test]
+fn test_default_with_fields() {
+    assert_cli::Assert::command(&[RARGS, "--json", "echo", "{id:-{LINENUM}}"])
+        .stdin("{\"id\": 7}\n{}\n{\"id\": \"\"}")
+        .stdout()
+        .is("7\n2\n3")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "echo", "{2:-{1}-{LN}}", "{$MISSING_VAR:-{1:-x}}"])
+        .stdin("a b\nc")
+        .stdout()
+        .is("b a\nc-2 c")
+        .unwrap();
+
+    // only one level of fields in defaults, also for --check
+    for args in [
+        &[RARGS, "echo", "{id:-{nope:-{LN}}}"][..],
+        &[RARGS, "--check", "echo", "{id:-{nope:-{LN}}}"][..],
+    ] {
+        assert_cli::Assert::command(args)
+            .stdin("a")
+            .fails_with(2)
+            .stderr()
+            .contains("invalid field: {id:-{nope:-{LN}}}, the fields in a default or conditional can't contain fields themselves")
+            .unwrap();
+    }
+}
+
+#[test]
+fn test_max_line_length() {
+    assert_cli::Assert::command(&[RARGS, "--max-line-length", "3", "echo", "{LN}:{}"])
+        .stdin("abc\nabcd\r\nab\r\n")
+        .stdout()
+        .is("1:abc\n3:ab")
+        .stderr()
+        .is("rargs: skip line 2: longer than 3 bytes")
+        .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--max-line-length",
+        "4",
+        "--on-long-line",
+        "truncate",
+        "--record-separator",
+        "::",
+        "echo",
+        "{}",
+    ])
+    .stdin("abcdefgh::ab€:lo:ng::x")
+    .stdout()
+    .is("abcd\nab\nx")
+    .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--max-line-length",
+        "2",
+        "--on-long-line",
+        "error",
+        "echo",
+    ])
+    .stdin("ab\nabc\nd")
+    .fails_with(1)
+    .stdout()
+    .is("")
+    .stderr()
+    .contains("rargs: line 2 is longer than 2 bytes")
+    .unwrap();
+}
+
+#[test]
+fn test_ignore_case() {
+    assert_cli::Assert::command(&[RARGS, "-i", "-p", "^key=(.*)", "echo", "{1}"])
+        .stdin("KEY=a\nKey=b")
+        .stdout()
+        .is("a\nb")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "-i", "-F", "x", "echo", "{1}-{2}|{2|sub:/B/c/g}"])
+        .stdin("aXb")
+        .stdout()
+        .is("a-b|c")
+        .unwrap();
+}
+
+#[test]
+fn test_dotall_multiline() {
+    let input = "id: 1\nname: a\n--\nid: 2\nname: b";
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--record-separator",
+        "\\n--\\n",
+        "--dotall",
+        "--multiline",
+        "-p",
+        "^id: (.*?)$.*^name: (.*?)$",
+        "echo",
+        "{2}-{1}",
+    ])
+    .stdin(input)
+    .stdout()
+    .is("a-1\nb-2")
+    .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--record-separator",
+        "\\n--\\n",
+        "-p",
+        "^id: (.*?)$.*^name: (.*?)$",
+        "echo",
+        "{2}-{1}",
+    ])
+    .stdin(input)
+    .stdout()
+    .is("-\n-")
+    .unwrap();
+}
+
+#[test]
+fn test_emit_json() {
+    assert_cli::Assert::command(&[RARGS, "-p", r"(?P<user>\w+)=(\d+)?", "--emit-json"])
+        .stdin("alice=42\nb=")
+        .stdout()
+        .is(
+            r#"{"fields":["alice","42"],"groups":{"user":"alice"},"linenum":1}
+{"fields":["b"],"groups":{"user":"b"},"linenum":2}"#,
+        )
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--emit-json", "echo"])
+        .fails_with(1)
+        .unwrap();
+}
+
+#[test]
+fn test_output_separator() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-s",
+        ",",
+        "--output-separator",
+        "\\t",
+        "echo",
+        "{..}",
+        "{1|on::}",
+        "{..|on::}",
+    ])
+    .stdin("a:b c")
+    .stdout()
+    .is("a:b,c a\tb a\tb,c")
+    .unwrap();
+}
+
+#[test]
+fn test_escaped_braces() {
+    assert_cli::Assert::command(&[RARGS, "echo", "{{1}}", "{{{1}}}", "{{print}}", "{1:-{2}}}"])
+        .stdin("a b")
+        .stdout()
+        .is("{1} {a} {print} a}")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "-I", "%{ }%", "echo", "%{%{1}%}%-%{1}%"])
+        .stdin("a")
+        .stdout()
+        .is("%{1}%-a")
+        .unwrap();
+}
+
+#[test]
+fn test_fail_fast() {
+    let start = std::time::Instant::now();
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-P",
+        "3",
+        "--fail-fast",
+        "sh",
+        "-c",
+        "[ $0 = fail ] && exit 7; exec sleep $0",
+        "{}",
+    ])
+    .stdin("60\nfail\n60\n60")
+    .fails_with(7)
+    .unwrap();
+    // the other commands were stopped after the failure, long before they would have finished
+    assert!(start.elapsed() < std::time::Duration::from_secs(30));
+}
+
+#[test]
+fn test_delimiter_chars() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--delimiter-chars",
+        ",;|]-",
+        "echo",
+        "{1}+{2}+{3}+{4}+{5}",
+    ])
+    .stdin("a,b;;c|d]-e")
+    .stdout()
+    .is("a+b+c+d+e")
+    .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--delimiter-chars",
+        ",;",
+        "--keep-empty-fields",
+        "echo",
+        "{1}+{2}+{3}+{4}",
+    ])
+    .stdin("a,b;;c")
+    .stdout()
+    .is("a+b++c")
+    .unwrap();
+}
+
+#[test]
+fn test_keep_empty_fields() {
+    assert_cli::Assert::command(&[RARGS, "-d", ",", "echo", "{#}:{..:+}"])
+        .stdin("a,,b,")
+        .stdout()
+        .is("3:a++b")
+        .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-F",
+        "\t",
+        "--keep-empty-fields",
+        "echo",
+        "{#}:{-1}{-2}:{@3}",
+    ])
+    .stdin("a\t\tb\t")
+    .stdout()
+    .is("4:b:3")
+    .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--keep-empty-fields", "echo", "{#}:{2}"])
+        .stdin(" a  b")
+        .stdout()
+        .is("4:a")
+        .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-p",
+        r"(\w)=(\d)?(\w)?",
+        "--keep-empty-fields",
+        "echo",
+        "{1}{2}{3}:{#}",
+    ])
+    .stdin("a=b")
+    .stdout()
+    .is("ab:3")
+    .unwrap();
+}
+
+#[test]
+fn test_min_fields() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--min-fields",
+        "3",
+        "--exit-on-missing-field",
+        "-d",
+        ",",
+        "echo",
+        "{#}:{1}:{3}:{c:-none}",
+    ])
+    .stdin("a,b,c\nd\ne,f,g,h")
+    .stdout()
+    .is("3:a:c:none\n3:d::none\n4:e:g:none")
+    .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--header",
+        "--min-fields",
+        "2",
+        "-d",
+        ",",
+        "echo",
+        "[{b}]",
+    ])
+    .stdin("a,b\nx")
+    .stdout()
+    .is("[]")
+    .unwrap();
+}
+
+#[test]
+fn test_number_format_filters() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "echo",
+        "{1|commas}",
+        "{2|commas}",
+        "{3|commas}",
+        "{2|round:2}",
+        "{2|round}",
+        "{4|round:1}",
+        "{2|round:2|commas}",
+        "{5|commas}",
+        "{5|round:2}",
+    ])
+    .stdin("123 1234567.891 -1000 -0.01 n/a")
+    .stdout()
+    .is("123 1,234,567.891 -1,000 1234567.89 1234568 0.0 1,234,567.89 n/a n/a")
+    .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "echo", "{1|round:x}"])
+        .fails_with(2)
+        .stderr()
+        .contains("invalid filter argument: round:x")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "echo", "{1|round:2000}"])
+        .fails_with(2)
+        .stderr()
+        .contains("invalid filter argument: round:2000, at most 1024")
+        .unwrap();
+}
+
+#[test]
+fn test_output0() {
+    assert_cli::Assert::command(&[RARGS, "-0", "--output0", "-e", "echo", "{}"])
+        .stdin("a\nb\0c")
+        .stdout()
+        .is("echo a\nb\0echo c\0")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--output0", "--quoted-output", "-e", "echo", "{}"])
+        .stdin("a b")
+        .stdout()
+        .is("echo 'a b' # line 1\0")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--output0", "-t", "true", "{}"])
+        .stdin("a\nb")
+        .stderr()
+        .is("+ true a # line 1\0+ true b # line 2\0")
+        .unwrap();
+}
+
+#[test]
+fn test_check() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--check",
+        "echo",
+        "x{1}",
+        "{name:-none}",
+        "{2...}",
+        "{LN|add:1}",
+    ])
+    .stdout()
+    .is("arg 1: x{1}\n  {1}: numbered field 1\narg 2: {name:-none}\n  {name:-none}: named field name, default \"none\"\narg 3: {2...}\n  {2...}: range 2.. split into arguments\narg 4: {LN|add:1}\n  {LN|add:1}: special variable LN, filtered by add")
+    .unwrap();
+
+    // open bounds are printed back as they are written
+    assert_cli::Assert::command(&[RARGS, "--check", "echo", "{..2}", "{...}", "{2..:,}"])
+        .stdout()
+        .is("arg 1: {..2}\n  {..2}: range ..2 joined by the separator\narg 2: {...}\n  {...}: range .. split into arguments\narg 3: {2..:,}\n  {2..:,}: range 2.. joined by \",\"")
+        .unwrap();
+
+    // conditionals and filter chains as well, with the delimiters of the template
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-I",
+        "[[ ]]",
+        "--check",
+        "echo",
+        "[[x?y]]",
+        "[[id:-[[LN]]]]",
+        "[[1|trim|upper]]",
+    ])
+    .stdout()
+    .is("arg 1: [[x?y]]\n  [[x?y]]: conditional on named field x\narg 2: [[id:-[[LN]]]]\n  [[id:-[[LN]]]]: conditional on named field id\narg 3: [[1|trim|upper]]\n  [[1|trim|upper]]: numbered field 1, filtered by trim, upper")
+    .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--check", "echo", "{1..x}"])
+        .fails_with(2)
+        .stderr()
+        .contains("arg 1: malformed field: {1..x}")
+        .unwrap();
+}
+
+#[test]
+fn test_invalid_field_index() {
+    assert_cli::Assert::command(&[RARGS, "echo", "{99999999999999999999}"])
+        .fails_with(2)
+        .stderr()
+        .is("rargs: invalid field index: {99999999999999999999}")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "echo", "{1..99999999999999999999|upper}"])
+        .fails_with(2)
+        .stderr()
+        .contains("invalid field index: {1..99999999999999999999}")
+        .unwrap();
+}
+
+#[test]
+fn test_large_field_indexes() {
+    // more fields than an i16 could index
+    let line = (1..=40000)
+        .map(|i| format!("f{}", i))
+        .collect::<Vec<_>>()
+        .join(" ");
+    assert_cli::Assert::command(&[RARGS, "echo", "{40000}", "{-40000}", "{39999..}"])
+        .stdin(line.as_str())
+        .stdout()
+        .is("f40000 f1 f39999 f40000")
+        .unwrap();
+
+    // beyond the i32 boundaries
+    assert_cli::Assert::command(&[
+        RARGS,
+        "echo",
+        "[{3000000000}]",
+        "[{3000000000..}]",
+        "[{-3000000000..-2}]",
+        "[{1|nth:3000000000}]",
+    ])
+    .stdin("a b c")
+    .stdout()
+    .is("[] [] [a b] []")
+    .unwrap();
+}
+
+#[test]
+fn test_ci_names() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--ci-names",
+        "--header",
+        "-d",
+        ",",
+        "echo",
+        "{Email}",
+        "{email}",
+        "{NAME:-none}",
+        "{Id}",
+        "{LN}",
+    ])
+    .stdin("Name,EMAIL,id,ID\nann,ann@example.com,1,2")
+    .stdout()
+    .is("ann@example.com ann@example.com ann 2 2")
+    .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--ci-names",
+        "-p",
+        "(?P<Key>\\w+)=(?P<value>\\w+)",
+        "echo",
+        "{key}",
+        "{VALUE}",
+        "{@Value}",
+    ])
+    .stdin("a=b")
+    .stdout()
+    .is("a b 2")
+    .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--ci-names", "-p", "(?P<a>x)(?P<A>y)", "echo"])
+        .fails_with(2)
+        .stderr()
+        .contains("--ci-names: the groups a and A differ only by case")
+        .unwrap();
+}
+
+#[test]
+fn test_print0_from_stdout() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--print0-from-stdout",
+        "printf",
+        "%s\\n%s",
+        "{}",
+        "x",
+    ])
+    .stdin("a b\nc")
+    .stdout()
+    .is("a b\0x\0c\0x\0")
+    .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--print0-from-stdout",
+        "--keep-order",
+        "--prefix",
+        "-P",
+        "2",
+        "echo",
+        "{}",
+    ])
+    .stdin("a\nb")
+    .stdout()
+    .is("[1] a\0[2] b\0")
+    .unwrap();
+}
+
+#[test]
+fn test_join_filter() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "printf",
+        "%s\\n",
+        "{2...4|upper|join:,}",
+        "{1|on:;|nonempty|join:+}",
+        "{2...3|join}",
+        "{9...|join:,}",
+        "x",
+    ])
+    .stdin("a;;b c d e")
+    .stdout()
+    .is("C,D,E\na+b\nc d\nx")
+    .unwrap();
+}
+
+#[test]
+fn test_dedupe() {
+    assert_cli::Assert::command(&[RARGS, "--dedupe", "echo", "{LN}", "{}"])
+        .stdin("a 1\nb 2\na 1\nc 1\nb 2")
+        .stdout()
+        .is("1 a 1\n2 b 2\n4 c 1")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--dedupe-field", "2", "echo", "{}"])
+        .stdin("a 1\nb 2\na 1\nc 1\nb 2")
+        .stdout()
+        .is("a 1\nb 2")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--dedupe", "--dedupe-window", "2", "echo", "{}"])
+        .stdin("a\nb\na\nc\nb\na")
+        .stdout()
+        .is("a\nb\nc\na")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--dedupe-window", "2", "echo"])
+        .fails_with(2)
+        .stderr()
+        .contains("--dedupe-window requires --dedupe or --dedupe-field")
+        .unwrap();
+}
+
+#[test]
+fn test_before_and_after() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--before",
+        "echo start",
+        "--after",
+        "echo done $RARGS_COMMANDS $RARGS_FAILURES",
+        "sh",
+        "-c",
+        "echo $0; exit $0",
+        "{}",
+    ])
+    .stdin("0\n1\n0")
+    .fails_with(123)
+    .stdout()
+    .is("start\n0\n1\n0\ndone 3 1")
+    .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--before",
+        "exit 3",
+        "--after",
+        "echo done",
+        "echo",
+        "{}",
+    ])
+    .stdin("x")
+    .fails_with(123)
+    .stdout()
+    .is("")
+    .stderr()
+    .contains("rargs: --before failed")
+    .unwrap();
+}
+
+#[test]
+fn test_after_on_interrupt() {
+    let mut child = std::process::Command::new(RARGS)
+        .args(["--after", "echo done $RARGS_COMMANDS", "echo", "{}"])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    // keep stdin open so that only the interrupt stops the input
+    let mut stdin = child.stdin.take().unwrap();
+    std::io::Write::write_all(&mut stdin, b"1\n").unwrap();
+
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    let status = std::process::Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(130));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "1\ndone 1\n");
+    drop(stdin);
+}
+
+#[test]
+fn test_rate() {
+    let start = std::time::Instant::now();
+    assert_cli::Assert::command(&[RARGS, "-P", "4", "--rate", "10", "echo", "{}"])
+        .stdin("1\n2\n3\n4")
+        .stdout()
+        .contains("4")
+        .unwrap();
+    assert!(start.elapsed() >= std::time::Duration::from_millis(300));
+
+    // without the burst the commands would be 10 seconds apart
+    let start = std::time::Instant::now();
+    assert_cli::Assert::command(&[RARGS, "--rate", "0.1", "--rate-burst", "3", "echo", "{}"])
+        .stdin("1\n2\n3")
+        .stdout()
+        .is("1\n2\n3")
+        .unwrap();
+    assert!(start.elapsed() < std::time::Duration::from_secs(10));
+
+    assert_cli::Assert::command(&[RARGS, "--rate-burst", "3", "echo"])
+        .fails_with(2)
+        .stderr()
+        .contains("--rate-burst requires --rate")
+        .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--rate", "1e-20", "echo"])
+        .fails_with(2)
+        .stderr()
+        .is("rargs: --rate is too low")
+        .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--rate",
+        "1e-12",
+        "--rate-burst",
+        "100000000",
+        "echo",
+    ])
+    .fails_with(2)
+    .stderr()
+    .is("rargs: --rate-burst is too large for the --rate")
+    .unwrap();
+}
+
+#[test]
+fn test_chunks_filter() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "printf",
+        "%s\\n",
+        "{1...1|chunks:3}",
+        "{2|chunks:2}",
+        "{2...2|chunks:9}",
+        "x{3...3|chunks:2}",
+    ])
+    .stdin("abcdefg ÄÖÜß")
+    .stdout()
+    .is("abc\ndef\ng\nÄÖ Üß\nÄÖÜß\nx")
+    .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "echo", "{1|chunks:0}"])
+        .fails_with(2)
+        .stderr()
+        .contains("invalid filter argument: chunks:0")
+        .unwrap();
+}
+
+#[test]
+fn test_ascii_only() {
+    assert_cli::Assert::command(&[RARGS, "--ascii-only", "echo", "{LN}", "{}"])
+        .stdin("abc\nnaïve\nxyz")
+        .stdout()
+        .is("1 abc\n3 xyz")
+        .stderr()
+        .is("rargs: skip line 2: non-ASCII character at byte 2")
+        .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--ascii-only",
+        "--on-non-ascii",
+        "error",
+        "echo",
+        "{}",
+    ])
+    .stdin("abc\nnaïve\nxyz")
+    .fails_with(1)
+    .stdout()
+    .is("abc")
+    .stderr()
+    .contains("rargs: line 2 has a non-ASCII character at byte 2")
+    .unwrap();
+}
+
+#[test]
+fn test_output_checks() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--success-if-contains",
+        "ok",
+        "sh",
+        "-c",
+        "echo $0; [ $0 = a ] && echo ok; true",
+        "{}",
+    ])
+    .stdin("a\nb")
+    .fails_with(123)
+    .stdout()
+    .is("a\nok\nb")
+    .stderr()
+    .contains("rargs: output without \"ok\"")
+    .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--success-if-empty",
+        "--halt-on-error",
+        "sh",
+        "-c",
+        "[ $0 = b ] && echo oops; true",
+        "{}",
+    ])
+    .stdin("a\nb\nc")
+    .fails_with(123)
+    .stdout()
+    .is("oops")
+    .stderr()
+    .contains("rargs: unexpected output")
+    .unwrap();
+}
+
+#[test]
+fn test_match_filters() {
+    assert_cli::Assert::command(&[RARGS, "--match", "^ERROR", "echo", "{LN}", "{2}"])
+        .stdin("ERROR a\nINFO b\nERROR c\nerror d")
+        .stdout()
+        .is("1 a\n2 c")
+        .unwrap();
+
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-i",
+        "--match",
+        "^ERROR",
+        "--no-match",
+        "c$",
+        "--number-skipped",
+        "echo",
+        "{LN}",
+        "{2}",
+    ])
+    .stdin("ERROR a\nINFO b\nERROR c\nerror d")
+    .stdout()
+    .is("1 a\n4 d")
+    .unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--header", "--match", "^y", "echo", "{name}"])
+        .stdin("name\nx\ny")
+        .stdout()
+        .is("y")
+        .unwrap();
+}
+
+#[test]
+fn test_match_groups() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-p",
+        "(?P<key>\\w+)=(\\w+)",
+        "echo",
+        "{match.2.1}",
+        "{match.2.2}",
+        "{match.3.key}",
+        "{match.1.0}",
+        "{match.4.1:-none}",
+        "{3}",
+        "{key}",
+    ])
+    .stdin("a=1, b=2, c=3")
+    .stdout()
+    .is("b 2 c a=1 none b c")
+    .unwrap();
+}
+
+#[test]
+fn test_print_unmatched() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--print-unmatched",
+        "-p",
+        r"(\w)=(\d)",
+        "echo",
+        "[{1}]",
+    ])
+    .stdin("a=1\nxyz\nb=2\n")
+    .stdout()
+    .is("[a]\n[]\n[b]")
+    .stderr()
+    .is("rargs: unmatched line 2: xyz")
+    .unwrap();
+}
+
+#[test]
+fn test_chunk_output() {
+    let dir = std::env::temp_dir().join("rargs-test-chunk-output");
+    let template = format!("{}/{{1}}.txt", dir.display());
+    assert_cli::Assert::command(&[RARGS, "-j", "2", "--chunk-output", &template, "echo", "{2}"])
+        .stdin("a 1\nb 2\na 3\nb 4\na 5")
+        .stdout()
+        .is("")
+        .unwrap();
+
+    let a = std::fs::read_to_string(dir.join("a.txt")).unwrap();
+    let b = std::fs::read_to_string(dir.join("b.txt")).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    // the order in a shard is the order the commands finish
+    let sort = |content: &str| {
+        let mut lines: Vec<&str> = content.lines().collect();
+        lines.sort();
+        lines.join(" ")
+    };
+    assert_eq!(sort(&a), "1 3 5");
+    assert_eq!(sort(&b), "2 4");
+}
+
+#[test]
+fn test_dispatch_batch() {
+    assert_cli::Assert::command(&[
+        RARGS,
+        "-P",
+        "2",
+        "--dispatch-batch",
+        "3",
+        "--keep-order",
+        "echo",
+        "{}",
+    ])
+    .stdin("1\n2\n3\n4\n5\n6\n7")
+    .stdout()
+    .is("1\n2\n3\n4\n5\n6\n7")
+    .unwrap();
+
+    // the pending commands are run with the header of their own input
+    let first = std::env::temp_dir().join("rargs-test-dispatch-batch-1");
+    let second = std::env::temp_dir().join("rargs-test-dispatch-batch-2");
+    std::fs::write(&first, "name id\na 1\nb 2\n").unwrap();
+    std::fs::write(&second, "id name\n3 c\n").unwrap();
+    assert_cli::Assert::command(&[
+        RARGS,
+        "--header",
+        "--dispatch-batch",
+        "5",
+        "-a",
+        first.to_str().unwrap(),
+        "-a",
+        second.to_str().unwrap(),
+        "echo",
+        "{name}",
+    ])
+    .stdout()
+    .is("a\nb\nc")
+    .unwrap();
+    std::fs::remove_file(&first).unwrap();
+    std::fs::remove_file(&second).unwrap();
+
+    assert_cli::Assert::command(&[RARGS, "--dispatch-batch", "0", "echo"])
+        .fails_with(2)
+        .stderr()
+        .contains("--dispatch-batch must be at least 1")
+        .unwrap();
+}